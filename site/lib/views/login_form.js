@@ -105,11 +105,11 @@ export default ({ attrs: { submit } }) => {
             webdm.open_session(webdm.sessions[0]).then(() => {
                 console.log("Session started");
                 webdm.exit();
-            }).catch(() => {
-                console.error("Could not open session");
+            }).catch(err => {
+                console.error("Could not open session:", err.code, err.message);
             });
-        }).catch(() => {
-            console.error("Failed to log in");
+        }).catch(err => {
+            console.error("Failed to log in:", err.code, err.message);
         }).canceled(() => {
             console.log("Canceled log in");
         });
@@ -1,17 +1,50 @@
+mod conversation;
+mod failure;
+
+pub(crate) use conversation::{Answer, Conversation, Prompt};
+pub(crate) use failure::Failure;
+
 use ipc_channel::ipc::{channel, IpcReceiver, IpcSender};
+use log::{debug, error, info, warn};
 use pam::Converse;
 use serde::{Deserialize, Serialize};
 use users::os::unix::UserExt;
 
+use crate::power;
+use crate::safe;
+use crate::session::{SessionEntry, SessionType};
+use crate::xauth;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
 static PAM_SERVICE_NAME: &str = "webdm";
+static PAM_AUTOLOGIN_SERVICE_NAME: &str = "webdm-autologin";
 
 pub(crate) struct Auth<'a> {
-    pam: pam::Authenticator<'a, pam::PasswordConv>,
+    pam: pam::Authenticator<'a, Conversation>,
     recv: IpcReceiver<Message<Request>>,
     send: IpcSender<Message<Request>>,
-    callbacks: IpcSender<Message<bool>>,
+    callbacks: IpcSender<Message<Reply>>,
+    answers: Rc<IpcReceiver<Answer>>,
     display: String,
     vtnr: u8,
+    autologin: Option<String>,
+    cookie: Option<xauth::Cookie>,
+    power: Box<dyn power::Backend>,
+    authenticated: bool,
+    expired: bool,
+    /// Session opened for the authenticated user, the only one the main process will start
+    opened: Option<SessionEntry>,
+    /// A PAM session was opened but could not be set up. It is never started and is closed along
+    /// with the authenticator.
+    half_open: bool,
+    /// Earliest time the greeter may request automatic login
+    autologin_at: Option<Instant>,
+    /// Set once the greeter canceled automatic login, later requests for it are refused
+    autologin_canceled: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -22,8 +55,26 @@ pub(crate) struct Message<T> {
 
 #[derive(Deserialize, Serialize)]
 pub(crate) enum Request {
-    OpenSession,
-    Login { username: String, password: String },
+    /// Opens the discovered session with this key, the greeter never names the command to run
+    OpenSession {
+        key: u64,
+    },
+    Login {
+        username: String,
+        password: Option<String>,
+    },
+    ChangePassword,
+    Autologin,
+    CancelAutologin,
+    Power(power::Action),
+    /// Sent by the main process itself once the greeter process has exited
+    GreeterExited,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) enum Reply {
+    Prompt(Prompt),
+    Done(Result<(), Failure>),
 }
 
 #[derive(Debug)]
@@ -36,7 +87,7 @@ pub(crate) enum AuthError {
 pub(crate) enum DrainError {
     Pam(pam::PamError),
     FailedCallback(ipc_channel::Error),
-    InvalidUsername(std::ffi::NulError),
+    ChannelClosed(ipc_channel::Error),
 }
 
 impl From<std::io::Error> for AuthError {
@@ -51,15 +102,6 @@ impl From<pam::PamError> for AuthError {
     }
 }
 
-impl From<pam::SetCredentialsError> for DrainError {
-    fn from(err: pam::SetCredentialsError) -> Self {
-        match err {
-            pam::SetCredentialsError::PamError(err) => DrainError::Pam(err),
-            pam::SetCredentialsError::InvalidUsername(err) => DrainError::InvalidUsername(err),
-        }
-    }
-}
-
 impl From<ipc_channel::Error> for DrainError {
     fn from(err: ipc_channel::Error) -> Self {
         DrainError::FailedCallback(err)
@@ -80,104 +122,377 @@ impl<'a> Auth<'a> {
     pub(crate) fn create(
         display: String,
         vtnr: u8,
-    ) -> Result<(IpcReceiver<Message<bool>>, Self), AuthError> {
+        autologin: Option<String>,
+        cookie: Option<xauth::Cookie>,
+        power: Box<dyn power::Backend>,
+    ) -> Result<(IpcReceiver<Message<Reply>>, IpcSender<Answer>, Self), AuthError> {
         let (send, recv) = channel()?;
         let (callbacks, cb_recv) = channel()?;
+        let (answers, answer_recv) = channel()?;
+        let answer_recv = Rc::new(answer_recv);
 
         Ok((
             cb_recv,
+            answers,
             Self {
-                pam: pam::Authenticator::with_password(PAM_SERVICE_NAME)?,
+                pam: pam::Authenticator::with_handler(
+                    PAM_SERVICE_NAME,
+                    Conversation::new(callbacks.clone(), answer_recv.clone()),
+                )?,
                 callbacks,
+                answers: answer_recv,
                 recv,
                 send,
                 display,
                 vtnr,
+                autologin,
+                cookie,
+                power,
+                authenticated: false,
+                expired: false,
+                opened: None,
+                half_open: false,
+                autologin_at: None,
+                autologin_canceled: false,
             },
         ))
     }
 
-    pub fn into_pam(self) -> pam::Authenticator<'a, pam::PasswordConv> {
+    pub fn into_pam(self) -> pam::Authenticator<'a, Conversation> {
         self.pam
     }
 
+    /// Allows the greeter to request automatic login once `delay` has passed from now.
+    pub(crate) fn schedule_autologin(&mut self, delay: Duration) {
+        self.autologin_at = Some(Instant::now() + delay);
+    }
+
     pub(crate) fn sender(&self) -> IpcSender<Message<Request>> {
         self.send.clone()
     }
 
-    pub(crate) fn pam(&mut self) -> &mut pam::Authenticator<'a, pam::PasswordConv> {
+    pub(crate) fn pam(&mut self) -> &mut pam::Authenticator<'a, Conversation> {
         &mut self.pam
     }
 
-    pub(crate) fn drain(&mut self) -> Result<ipc_channel::Error, DrainError> {
+    /// Handles requests from the greeter, blocking until it has exited.
+    pub(crate) fn serve(
+        &mut self,
+        sessions: &HashMap<u64, SessionEntry>,
+    ) -> Result<(), DrainError> {
         loop {
-            let result = self.recv.try_recv();
-
-            match result {
-                Ok(msg) => match msg.message {
-                    Request::OpenSession => {
-                        println!("Attempting to open PAM session");
-
-                        let username = self.pam.handler().username().to_string();
-
-                        self.pam.env("XDG_SESSION_TYPE", "x11")?;
-                        self.pam.env("XDG_SESSION_CLASS", "user")?;
-                        self.pam.env("XDG_VTNR", &self.vtnr.to_string())?;
-                        self.pam.env("XDG_SEAT", "seat0")?;
-
-                        let session = self.pam.open_session();
-
-                        self.pam.env("DISPLAY", &self.display)?;
-                        self.pam.env("USER", &username)?;
-
-                        let mut opened = false;
-
-                        match session {
-                            Ok(_) => {
-                                println!("Getting username");
-
-                                println!("Looking up user");
-                                if let Some(user) = users::get_user_by_name(&username) {
-                                    println!("Setting PAM envs");
-
-                                    self.pam.env("SHELL", &user.shell().to_string_lossy())?;
-                                    self.pam.env("HOME", &user.home_dir().to_string_lossy())?;
-                                    self.pam.env("PWD", &user.home_dir().to_string_lossy())?;
-
-                                    opened = true;
-                                } else {
-                                    eprintln!(
-                                        "Could not find user '{}' in user database",
-                                        username
-                                    );
-                                }
-                            }
-                            Err(_) => {
-                                eprintln!("Failed to open PAM session");
-                            }
-                        }
-
-                        self.callbacks.send(Message {
-                            id: msg.id,
-                            message: opened,
-                        })?;
+            let msg = self.recv.recv().map_err(DrainError::ChannelClosed)?;
+
+            if let Request::GreeterExited = msg.message {
+                return Ok(());
+            }
+
+            if let Err(err) = self.handle(msg, sessions) {
+                error!("PAM error: {:?}", err);
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        msg: Message<Request>,
+        sessions: &HashMap<u64, SessionEntry>,
+    ) -> Result<(), DrainError> {
+        let allowed = match msg.message {
+            Request::Power(_) => true,
+            _ => self.opened.is_none() && !self.half_open,
+        };
+
+        if !allowed {
+            warn!("Ignoring request from greeter, a session has already been opened");
+
+            self.callbacks.send(Message {
+                id: msg.id,
+                message: Reply::Done(Err(Failure::Session)),
+            })?;
+            return Ok(());
+        }
+
+        match msg.message {
+            Request::OpenSession { key } => {
+                let opened = match sessions.get(&key) {
+                    Some(session) => self.open_session(session),
+                    None => {
+                        warn!("Greeter requested unknown session {}", key);
+                        Err(Failure::Session)
                     }
-                    Request::Login { username, password } => {
-                        self.pam
-                            .handler_mut()
-                            .set_credentials(username.clone(), password.clone())?;
-
-                        println!("Attempting to authenticate user '{}' with PAM", username);
-                        let auth = self.pam.authenticate();
-
-                        self.callbacks.send(Message {
-                            id: msg.id,
-                            message: auth.is_ok(),
-                        })?;
+                };
+
+                self.callbacks.send(Message {
+                    id: msg.id,
+                    message: Reply::Done(opened),
+                })?;
+            }
+            Request::Login { username, password } => {
+                let auth = self.authenticate(Some(msg.id), username, password);
+
+                self.callbacks.send(Message {
+                    id: msg.id,
+                    message: Reply::Done(auth),
+                })?;
+            }
+            Request::Autologin => {
+                let auth = match self.autologin_at {
+                    _ if self.autologin_canceled => {
+                        warn!("Ignoring automatic login request, it has been canceled");
+                        Err(Failure::Canceled)
+                    }
+                    None => {
+                        warn!("Ignoring automatic login request, it has not been scheduled");
+                        Err(Failure::NoAutologin)
+                    }
+                    Some(at) if Instant::now() < at => {
+                        warn!("Ignoring automatic login request before its delay has passed");
+                        Err(Failure::AutologinPending)
                     }
-                },
-                Err(err) => return Ok(err),
+                    Some(_) => self.autologin(Some(msg.id)),
+                };
+
+                self.callbacks.send(Message {
+                    id: msg.id,
+                    message: Reply::Done(auth),
+                })?;
+            }
+            Request::CancelAutologin => {
+                if !self.autologin_canceled {
+                    info!("Automatic login canceled by the greeter");
+                    self.autologin_canceled = true;
+                }
+
+                self.callbacks.send(Message {
+                    id: msg.id,
+                    message: Reply::Done(Ok(())),
+                })?;
+            }
+            Request::Power(action) => {
+                info!("Performing power action {:?}", action);
+
+                let result = self.power.perform(action).map_err(|e| {
+                    warn!("Power action {:?} failed: {}", action, e);
+                    Failure::Power(e.to_string())
+                });
+
+                self.callbacks.send(Message {
+                    id: msg.id,
+                    message: Reply::Done(result),
+                })?;
+            }
+            Request::ChangePassword => {
+                let changed = if self.expired {
+                    info!(
+                        "Attempting to change expired password of user '{}'",
+                        self.pam.handler().username()
+                    );
+
+                    self.pam.handler_mut().resume(Some(msg.id));
+                    let change = self
+                        .pam
+                        .change_authtok(pam::PamFlag::CHANGE_EXPIRED_AUTHTOK)
+                        .and_then(|_| self.pam.acct_mgmt())
+                        .map_err(Failure::from);
+                    self.pam.handler_mut().end();
+
+                    if let Err(ref failure) = change {
+                        warn!("Password change failed: {}", failure.message());
+                    }
+
+                    self.authenticated = change.is_ok();
+                    self.expired = change.is_err();
+                    change
+                } else {
+                    warn!("Password change requested, but no expired password to change");
+                    Err(Failure::NotAuthenticated)
+                };
+
+                self.callbacks.send(Message {
+                    id: msg.id,
+                    message: Reply::Done(changed),
+                })?;
+            }
+            Request::GreeterExited => {}
+        }
+
+        Ok(())
+    }
+
+    /// Authenticates the configured autologin user through the `webdm-autologin` PAM service,
+    /// which is expected to not ask for a password.
+    pub(crate) fn autologin(&mut self, id: Option<u64>) -> Result<(), Failure> {
+        let username = match self.autologin {
+            Some(ref username) => username.clone(),
+            None => {
+                warn!("Automatic login requested, but it is not configured");
+                return Err(Failure::NoAutologin);
             }
+        };
+
+        self.pam = pam::Authenticator::with_handler(
+            PAM_AUTOLOGIN_SERVICE_NAME,
+            Conversation::new(self.callbacks.clone(), self.answers.clone()),
+        )?;
+
+        self.authenticate(id, username, None)
+    }
+
+    fn authenticate(
+        &mut self,
+        id: Option<u64>,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), Failure> {
+        info!("Attempting to authenticate user '{}' with PAM", username);
+
+        self.pam.handler_mut().begin(id, username, password);
+        let auth = self.pam.authenticate().and_then(|_| self.pam.acct_mgmt());
+        self.pam.handler_mut().end();
+
+        let auth = auth.map_err(Failure::from).and_then(|_| {
+            users::get_user_by_name(self.pam.handler().username())
+                .map(|_| ())
+                .ok_or(Failure::UnknownUser)
+        });
+
+        if let Err(ref failure) = auth {
+            warn!(
+                "Authentication of user '{}' failed: {}",
+                self.pam.handler().username(),
+                failure.message()
+            );
+        }
+
+        self.authenticated = auth.is_ok();
+        self.expired = auth == Err(Failure::PasswordExpired);
+        auth
+    }
+
+    fn pam_env(&mut self, name: &str) -> Option<String> {
+        let prefix = format!("{}=", name);
+
+        self.pam.environment()?.iter().find_map(|name_value| {
+            let name_value = name_value.to_str().ok()?;
+
+            if name_value.starts_with(&prefix) {
+                Some(name_value[prefix.len()..].to_owned())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Takes the session opened for the authenticated user. No other session may be started.
+    pub(crate) fn opened_session(&mut self) -> Result<SessionEntry, Failure> {
+        if !self.authenticated {
+            return Err(Failure::NotAuthenticated);
+        }
+
+        self.opened.take().ok_or(Failure::Session)
+    }
+
+    pub(crate) fn vtnr(&self) -> u8 {
+        self.vtnr
+    }
+
+    pub(crate) fn open_session(&mut self, session: &SessionEntry) -> Result<(), Failure> {
+        if !self.authenticated {
+            warn!("Session requested, but no user has been authenticated");
+            return Err(Failure::NotAuthenticated);
         }
+
+        let typ = session.typ;
+        info!(
+            "Attempting to open PAM {} session {}",
+            typ.as_str(),
+            session.id
+        );
+
+        self.session_env(session).map_err(|e| {
+            error!("Could not set PAM environment: {}", e);
+            Failure::Module(e.to_string())
+        })?;
+
+        if let Err(e) = self.pam.open_session() {
+            error!("Failed to open PAM session: {}", e);
+            return Err(Failure::Session);
+        }
+
+        if let Err(failure) = self.user_env(typ) {
+            error!(
+                "Could not set up PAM session, refusing it: {}",
+                failure.message()
+            );
+            self.half_open = true;
+            return Err(Failure::Session);
+        }
+
+        self.opened = Some(session.clone());
+        Ok(())
+    }
+
+    /// Environment the PAM modules see when opening the session.
+    fn session_env(&mut self, session: &SessionEntry) -> Result<(), pam::PamError> {
+        self.pam.env("XDG_SESSION_TYPE", session.typ.as_str())?;
+        self.pam.env("XDG_SESSION_CLASS", "user")?;
+        self.pam.env("XDG_VTNR", &self.vtnr.to_string())?;
+        self.pam.env("XDG_SEAT", "seat0")?;
+        self.pam.env("XDG_SESSION_DESKTOP", &session.id)?;
+        self.pam.env("DESKTOP_SESSION", &session.id)?;
+        self.pam.env("GDMSESSION", &session.id)?;
+
+        if !session.entry.desktop_names.is_empty() {
+            self.pam.env(
+                "XDG_CURRENT_DESKTOP",
+                &session.entry.desktop_names.join(":"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Environment of the user's session, set once the PAM session is open.
+    fn user_env(&mut self, typ: SessionType) -> Result<(), Failure> {
+        let username = self.pam.handler().username().to_string();
+
+        if typ == SessionType::X11 {
+            self.pam.env("DISPLAY", &self.display)?;
+        }
+
+        self.pam.env("USER", &username)?;
+        self.pam.env("LOGNAME", &username)?;
+
+        debug!("Looking up user");
+        let user = users::get_user_by_name(&username).ok_or_else(|| {
+            error!("Could not find user '{}' in user database", username);
+            Failure::UnknownUser
+        })?;
+
+        debug!("Setting PAM envs");
+        self.pam.env("SHELL", &user.shell().to_string_lossy())?;
+        self.pam.env("HOME", &user.home_dir().to_string_lossy())?;
+        self.pam.env("PWD", &user.home_dir().to_string_lossy())?;
+
+        if let (SessionType::X11, Some(cookie)) = (typ, self.cookie.clone()) {
+            let path = match self.pam_env("XDG_RUNTIME_DIR") {
+                Some(dir) => Path::new(&dir).join("Xauthority"),
+                None => user.home_dir().join(".Xauthority"),
+            };
+
+            info!("Writing session authority to {}", path.display());
+            // Written as the user, so root never follows links the user placed
+            let written = safe::libc::as_user_fs(user.uid(), user.primary_group_id(), || {
+                xauth::update(&path, &self.display, &cookie)
+            });
+
+            match written {
+                Ok(()) => self.pam.env("XAUTHORITY", &path.to_string_lossy())?,
+                Err(e) => warn!("Could not write {}: {}", path.display(), e),
+            }
+        }
+
+        Ok(())
     }
 }
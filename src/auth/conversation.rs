@@ -0,0 +1,153 @@
+use ipc_channel::ipc::{IpcReceiver, IpcSender};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use std::ffi::{CStr, CString};
+use std::rc::Rc;
+
+use super::{Message, Reply};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum Prompt {
+    Echo(String),
+    Blind(String),
+    Info(String),
+    Error(String),
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum Answer {
+    Respond(String),
+    Cancel,
+}
+
+impl Prompt {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        match self {
+            Prompt::Echo(text) => serde_json::json!({
+                "type": "prompt",
+                "echo": true,
+                "text": text,
+            }),
+            Prompt::Blind(text) => serde_json::json!({
+                "type": "prompt",
+                "echo": false,
+                "text": text,
+            }),
+            Prompt::Info(text) => serde_json::json!({
+                "type": "info",
+                "text": text,
+            }),
+            Prompt::Error(text) => serde_json::json!({
+                "type": "error",
+                "text": text,
+            }),
+        }
+    }
+}
+
+/// PAM conversation that forwards every message to the greeter and blocks until the theme
+/// answers over the `answers` channel.
+///
+/// The username and password given with a login request are used to answer the first echo and
+/// blind prompt respectively, the same way `pam::PasswordConv` does, so themes that only know
+/// about passwords keep working.
+pub(crate) struct Conversation {
+    events: IpcSender<Message<Reply>>,
+    answers: Rc<IpcReceiver<Answer>>,
+    id: Option<u64>,
+    username: String,
+    pending_username: bool,
+    password: Option<String>,
+}
+
+impl Conversation {
+    pub(crate) fn new(events: IpcSender<Message<Reply>>, answers: Rc<IpcReceiver<Answer>>) -> Self {
+        Conversation {
+            events,
+            answers,
+            id: None,
+            username: String::new(),
+            pending_username: false,
+            password: None,
+        }
+    }
+
+    pub(crate) fn begin(&mut self, id: Option<u64>, username: String, password: Option<String>) {
+        self.id = id;
+        self.username = username;
+        self.pending_username = true;
+        self.password = password;
+    }
+
+    pub(crate) fn resume(&mut self, id: Option<u64>) {
+        self.id = id;
+        self.pending_username = false;
+        self.password = None;
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.id = None;
+        self.pending_username = false;
+        self.password = None;
+    }
+
+    fn notify(&self, prompt: Prompt) -> Result<(), ()> {
+        let id = self.id.ok_or(())?;
+
+        self.events
+            .send(Message {
+                id,
+                message: Reply::Prompt(prompt),
+            })
+            .map_err(|e| error!("Could not forward PAM message to greeter: {}", e))
+    }
+
+    fn ask(&mut self, prompt: Prompt) -> Result<CString, ()> {
+        self.notify(prompt)?;
+
+        match self.answers.recv() {
+            Ok(Answer::Respond(answer)) => CString::new(answer).map_err(|_| ()),
+            Ok(Answer::Cancel) => Err(()),
+            Err(e) => {
+                error!("Could not receive answer from greeter: {}", e);
+                Err(())
+            }
+        }
+    }
+}
+
+fn lossy(msg: &CStr) -> String {
+    msg.to_string_lossy().into_owned()
+}
+
+impl pam::Converse for Conversation {
+    fn prompt_echo(&mut self, msg: &CStr) -> Result<CString, ()> {
+        if self.pending_username {
+            self.pending_username = false;
+            return CString::new(self.username.clone()).map_err(|_| ());
+        }
+
+        self.ask(Prompt::Echo(lossy(msg)))
+    }
+
+    fn prompt_blind(&mut self, msg: &CStr) -> Result<CString, ()> {
+        if let Some(password) = self.password.take() {
+            return CString::new(password).map_err(|_| ());
+        }
+
+        self.ask(Prompt::Blind(lossy(msg)))
+    }
+
+    fn info(&mut self, msg: &CStr) {
+        self.notify(Prompt::Info(lossy(msg))).ok();
+    }
+
+    fn error(&mut self, msg: &CStr) {
+        self.notify(Prompt::Error(lossy(msg))).ok();
+    }
+
+    fn username(&self) -> &str {
+        &self.username
+    }
+}
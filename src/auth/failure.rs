@@ -0,0 +1,83 @@
+use pam::PamReturnCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Failure {
+    WrongPassword,
+    UnknownUser,
+    AccountLocked,
+    AccountExpired,
+    PasswordExpired,
+    PasswordChange,
+    Canceled,
+    NotAuthenticated,
+    NoAutologin,
+    AutologinPending,
+    Session,
+    Power(String),
+    Module(String),
+}
+
+impl Failure {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Failure::WrongPassword => "wrong_password",
+            Failure::UnknownUser => "unknown_user",
+            Failure::AccountLocked => "account_locked",
+            Failure::AccountExpired => "account_expired",
+            Failure::PasswordExpired => "password_expired",
+            Failure::PasswordChange => "password_change",
+            Failure::Canceled => "canceled",
+            Failure::NotAuthenticated => "not_authenticated",
+            Failure::NoAutologin => "no_autologin",
+            Failure::AutologinPending => "autologin_pending",
+            Failure::Session => "session",
+            Failure::Power(_) => "power",
+            Failure::Module(_) => "module",
+        }
+    }
+
+    pub(crate) fn message(&self) -> String {
+        match self {
+            Failure::WrongPassword => "Wrong username or password".into(),
+            Failure::UnknownUser => "Unknown user".into(),
+            Failure::AccountLocked => "Account is locked".into(),
+            Failure::AccountExpired => "Account has expired".into(),
+            Failure::PasswordExpired => "Password has expired and must be changed".into(),
+            Failure::PasswordChange => "Could not change password".into(),
+            Failure::Canceled => "Authentication was canceled".into(),
+            Failure::NotAuthenticated => "No user has been authenticated".into(),
+            Failure::NoAutologin => "Automatic login is not configured".into(),
+            Failure::AutologinPending => "Automatic login delay has not passed yet".into(),
+            Failure::Session => "Could not open session".into(),
+            Failure::Power(msg) => format!("Power action failed: {}", msg),
+            Failure::Module(msg) => format!("Authentication module error: {}", msg),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "code": self.code(),
+            "message": self.message(),
+        })
+    }
+}
+
+impl From<pam::PamError> for Failure {
+    fn from(err: pam::PamError) -> Self {
+        match err.code() {
+            PamReturnCode::AUTH_ERR => Failure::WrongPassword,
+            PamReturnCode::USER_UNKNOWN => Failure::UnknownUser,
+            PamReturnCode::MAXTRIES | PamReturnCode::PERM_DENIED => Failure::AccountLocked,
+            PamReturnCode::ACCT_EXPIRED => Failure::AccountExpired,
+            PamReturnCode::NEW_AUTHTOK_REQD => Failure::PasswordExpired,
+            PamReturnCode::AUTHTOK_ERR
+            | PamReturnCode::AUTHTOK_RECOVERY_ERR
+            | PamReturnCode::AUTHTOK_LOCK_BUSY
+            | PamReturnCode::AUTHTOK_DISABLE_AGING => Failure::PasswordChange,
+            PamReturnCode::CONV_ERR | PamReturnCode::ABORT => Failure::Canceled,
+            PamReturnCode::SESSION_ERR => Failure::Session,
+            _ => Failure::Module(err.to_string()),
+        }
+    }
+}
@@ -1,96 +1,584 @@
+#[cfg(test)]
+mod tests;
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+static DESKTOP_ENTRY_GROUP: &str = "Desktop Entry";
+static DESKTOP_ACTION_PREFIX: &str = "Desktop Action ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum EntryType {
     Application,
+    Link,
+    Directory,
     Unknown,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Action {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) exec: Option<String>,
+    pub(crate) icon: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Command {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Entry {
     pub(crate) typ: EntryType,
     pub(crate) name: String,
     pub(crate) path: Option<PathBuf>,
-    pub(crate) exec: Option<String>,
+    pub(crate) exec: Option<Command>,
     pub(crate) icon: Option<String>,
     pub(crate) comment: Option<String>,
+    /// Localized `Name` and `Comment` values, indexed by locale
+    pub(crate) localized_name: HashMap<String, String>,
+    pub(crate) localized_comment: HashMap<String, String>,
+    pub(crate) try_exec: Option<String>,
+    pub(crate) hidden: bool,
+    pub(crate) no_display: bool,
+    pub(crate) only_show_in: Vec<String>,
+    pub(crate) not_show_in: Vec<String>,
+    /// Names of the desktop environment started by this session entry
+    pub(crate) desktop_names: Vec<String>,
+    pub(crate) actions: Vec<Action>,
 }
 
 #[derive(Debug)]
 pub(crate) enum EntryError {
     FileError(FileError),
-    MissingRequiredField,
+    MissingRequiredField(&'static str),
+    InvalidValue { key: &'static str, value: String },
+    Exec(ExecError),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ExecError {
+    Empty,
+    UnterminatedQuote,
+    InvalidEscape(char),
+    UnknownFieldCode(char),
+    TrailingPercent,
+}
+
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExecError::Empty => write!(f, "no program given"),
+            ExecError::UnterminatedQuote => write!(f, "unterminated quote"),
+            ExecError::InvalidEscape(c) => write!(f, "invalid escape '\\{}' inside quotes", c),
+            ExecError::UnknownFieldCode(c) => write!(f, "unknown field code '%{}'", c),
+            ExecError::TrailingPercent => write!(f, "'%' at end of line"),
+        }
+    }
 }
 
 impl Entry {
     pub(crate) fn parse(path: &Path) -> Result<Self, EntryError> {
-        let mut desktop = DesktopFile::new(path).map_err(EntryError::FileError)?;
+        let desktop = DesktopFile::new(path).map_err(EntryError::FileError)?;
+        let group = desktop
+            .group(DESKTOP_ENTRY_GROUP)
+            .ok_or(EntryError::MissingRequiredField(DESKTOP_ENTRY_GROUP))?;
+
+        let actions = group
+            .strings("Actions")
+            .unwrap_or_else(Vec::new)
+            .into_iter()
+            .filter_map(|id| {
+                let action = desktop.group(&format!("{}{}", DESKTOP_ACTION_PREFIX, id))?;
+
+                Some(Action {
+                    name: action.string("Name")?,
+                    exec: action.string("Exec"),
+                    icon: action.string("Icon"),
+                    id,
+                })
+            })
+            .collect();
+
+        let name = group
+            .string("Name")
+            .ok_or(EntryError::MissingRequiredField("Name"))?;
+        let icon = group.string("Icon");
+
+        let exec = match group.string("Exec") {
+            Some(exec) => {
+                Some(Command::parse(&exec, &name, icon.as_ref(), path).map_err(EntryError::Exec)?)
+            }
+            None => None,
+        };
 
         Ok(Entry {
-            typ: match desktop
-                .0
-                .remove("Type")
-                .ok_or(EntryError::MissingRequiredField)?
+            typ: match group
+                .string("Type")
+                .ok_or(EntryError::MissingRequiredField("Type"))?
                 .as_ref()
             {
                 "Application" => EntryType::Application,
+                "Link" => EntryType::Link,
+                "Directory" => EntryType::Directory,
                 _ => EntryType::Unknown,
             },
-            name: desktop
-                .0
-                .remove("Name")
-                .ok_or(EntryError::MissingRequiredField)?,
-            path: desktop.0.remove("Path").map(Into::into),
-            exec: desktop.0.remove("Exec"),
-            icon: desktop.0.remove("Icon"),
-            comment: desktop.0.remove("Comment"),
+            name,
+            path: group.string("Path").map(Into::into),
+            exec,
+            icon,
+            comment: group.string("Comment"),
+            localized_name: group.localized("Name"),
+            localized_comment: group.localized("Comment"),
+            try_exec: group.string("TryExec"),
+            hidden: group.boolean("Hidden")?.unwrap_or(false),
+            no_display: group.boolean("NoDisplay")?.unwrap_or(false),
+            only_show_in: group.strings("OnlyShowIn").unwrap_or_else(Vec::new),
+            not_show_in: group.strings("NotShowIn").unwrap_or_else(Vec::new),
+            desktop_names: group.strings("DesktopNames").unwrap_or_else(Vec::new),
+            actions,
+        })
+    }
+
+    /// The name to show for `locale`, falling back to the untranslated name.
+    pub(crate) fn name_in(&self, locale: Option<&str>) -> &str {
+        locale
+            .and_then(|locale| lookup_locale(&self.localized_name, locale))
+            .unwrap_or(&self.name)
+    }
+
+    pub(crate) fn comment_in(&self, locale: Option<&str>) -> Option<&str> {
+        locale
+            .and_then(|locale| lookup_locale(&self.localized_comment, locale))
+            .or_else(|| self.comment.as_ref().map(String::as_str))
+    }
+
+    /// Why this entry should not be shown in an environment with the given desktop names, if it
+    /// should be hidden at all.
+    pub(crate) fn exclusion_reason(&self, desktops: &[&str]) -> Option<String> {
+        if self.hidden {
+            return Some("it is marked Hidden".into());
+        }
+
+        if self.no_display {
+            return Some("it is marked NoDisplay".into());
+        }
+
+        if !self.only_show_in.is_empty()
+            && !self
+                .only_show_in
+                .iter()
+                .any(|desktop| desktops.contains(&desktop.as_str()))
+        {
+            return Some(format!(
+                "it is only shown in {}",
+                self.only_show_in.join(", ")
+            ));
+        }
+
+        if let Some(desktop) = self
+            .not_show_in
+            .iter()
+            .find(|desktop| desktops.contains(&desktop.as_str()))
+        {
+            return Some(format!("it is not shown in {}", desktop));
+        }
+
+        if self.exec.is_none() {
+            return Some("it has no Exec line".into());
+        }
+
+        if let Some(ref try_exec) = self.try_exec {
+            if find_executable(try_exec).is_none() {
+                return Some(format!("TryExec program '{}' is not installed", try_exec));
+            }
+        }
+
+        None
+    }
+}
+
+/// The locale used for messages according to `LC_ALL`, `LC_MESSAGES` and `LANG`, if it is not
+/// the C locale.
+pub(crate) fn environment_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .filter(|value| value != "C" && value != "POSIX")
+}
+
+/// Picks the value for `locale` (`lang_COUNTRY.ENCODING@MODIFIER`) by trying
+/// `lang_COUNTRY@MODIFIER`, `lang_COUNTRY`, `lang@MODIFIER` and `lang` in that order. The
+/// encoding is ignored.
+fn lookup_locale<'a>(values: &'a HashMap<String, String>, locale: &str) -> Option<&'a str> {
+    let (locale, modifier) = match locale.find('@') {
+        Some(at) => (&locale[..at], Some(&locale[at + 1..])),
+        None => (locale, None),
+    };
+    let locale = locale.split('.').next().unwrap_or(locale);
+    let (lang, country) = match locale.find('_') {
+        Some(underscore) => (&locale[..underscore], Some(&locale[underscore + 1..])),
+        None => (locale, None),
+    };
+
+    let mut candidates = vec![];
+
+    if let Some(country) = country {
+        if let Some(modifier) = modifier {
+            candidates.push(format!("{}_{}@{}", lang, country, modifier));
+        }
+        candidates.push(format!("{}_{}", lang, country));
+    }
+    if let Some(modifier) = modifier {
+        candidates.push(format!("{}@{}", lang, modifier));
+    }
+    candidates.push(lang.to_owned());
+
+    candidates
+        .iter()
+        .find_map(|candidate| values.get(candidate))
+        .map(String::as_str)
+}
+
+/// Quotes `arg` so that `/bin/sh` reads it back as a single word.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Splits an `Exec` value into arguments, honouring double quotes and the escapes allowed inside
+/// them.
+fn tokenize(exec: &str) -> Result<Vec<String>, ExecError> {
+    let mut args = vec![];
+    let mut current: Option<String> = None;
+    let mut chars = exec.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                }
+            }
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+
+                loop {
+                    match chars.next() {
+                        None => return Err(ExecError::UnterminatedQuote),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ '"') | Some(c @ '`') | Some(c @ '$') | Some(c @ '\\') => {
+                                arg.push(c)
+                            }
+                            Some(c) => return Err(ExecError::InvalidEscape(c)),
+                            None => return Err(ExecError::UnterminatedQuote),
+                        },
+                        Some(c) => arg.push(c),
+                    }
+                }
+            }
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if let Some(arg) = current {
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+/// Expands the field codes of a single argument. Sessions are never started with files or URLs,
+/// so those codes expand to nothing.
+fn expand(
+    arg: &str,
+    name: &str,
+    icon: Option<&String>,
+    location: &Path,
+) -> Result<Vec<String>, ExecError> {
+    match arg {
+        "%f" | "%F" | "%u" | "%U" => return Ok(vec![]),
+        "%i" => {
+            return Ok(icon
+                .map(|icon| vec!["--icon".to_owned(), icon.clone()])
+                .unwrap_or_else(Vec::new))
+        }
+        _ => {}
+    }
+
+    let mut expanded = String::new();
+    let mut chars = arg.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some('c') => expanded.push_str(name),
+            Some('k') => expanded.push_str(&location.to_string_lossy()),
+            Some('f') | Some('F') | Some('u') | Some('U') | Some('i') => {}
+            Some('d') | Some('D') | Some('n') | Some('N') | Some('v') | Some('m') => {}
+            Some(c) => return Err(ExecError::UnknownFieldCode(c)),
+            None => return Err(ExecError::TrailingPercent),
+        }
+    }
+
+    Ok(vec![expanded])
+}
+
+impl Command {
+    pub(crate) fn parse(
+        exec: &str,
+        name: &str,
+        icon: Option<&String>,
+        location: &Path,
+    ) -> Result<Self, ExecError> {
+        let mut args = vec![];
+
+        for arg in tokenize(exec)? {
+            args.extend(expand(&arg, name, icon, location)?);
+        }
+
+        let mut args = args.into_iter();
+        let program = args.next().ok_or(ExecError::Empty)?;
+
+        Ok(Command {
+            program,
+            args: args.collect(),
         })
     }
+
+    /// The program and its arguments quoted for `/bin/sh` and joined into a single line.
+    pub(crate) fn to_shell(&self) -> String {
+        std::iter::once(&self.program)
+            .chain(self.args.iter())
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
-struct DesktopFile(HashMap<String, String>);
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Looks up a program the way `execvp` would, either as a path or by searching `$PATH`.
+pub(crate) fn find_executable(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return if is_executable(&path) {
+            Some(path)
+        } else {
+            None
+        };
+    }
+
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| is_executable(path))
+    })
+}
 
 #[derive(Debug)]
 pub(crate) enum FileError {
     Io(std::io::Error),
-    BadFormat,
+    BadFormat { line: usize, reason: &'static str },
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FileError::Io(e) => write!(f, "{}", e),
+            FileError::BadFormat { line, reason } => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+/// The keys of a single group, indexed by key and optional locale, with values still escaped.
+pub(crate) struct Group {
+    entries: HashMap<(String, Option<String>), String>,
+}
+
+pub(crate) struct DesktopFile {
+    groups: Vec<(String, Group)>,
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn split_locale(key: &str) -> Option<(&str, Option<&str>)> {
+    match key.find('[') {
+        None => Some((key, None)),
+        Some(start) if key.ends_with(']') && start + 1 < key.len() - 1 => {
+            Some((&key[..start], Some(&key[start + 1..key.len() - 1])))
+        }
+        Some(_) => None,
+    }
+}
+
+/// Resolves `\s`, `\n`, `\t`, `\r` and `\\`. When `list` is set, also splits on unescaped `;`
+/// and resolves `\;`.
+fn unescape(value: &str, list: bool) -> Vec<String> {
+    let mut items = vec![];
+    let mut current = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('s') => current.push(' '),
+                Some('n') => current.push('\n'),
+                Some('t') => current.push('\t'),
+                Some('r') => current.push('\r'),
+                Some('\\') => current.push('\\'),
+                Some(';') if list => current.push(';'),
+                Some(other) => {
+                    current.push('\\');
+                    current.push(other);
+                }
+                None => current.push('\\'),
+            },
+            ';' if list => items.push(std::mem::replace(&mut current, String::new())),
+            c => current.push(c),
+        }
+    }
+
+    if !list || !current.is_empty() {
+        items.push(current);
+    }
+
+    items
+}
+
+impl Group {
+    fn raw(&self, key: &str) -> Option<&String> {
+        self.entries.get(&(key.to_owned(), None))
+    }
+
+    pub(crate) fn string(&self, key: &str) -> Option<String> {
+        self.raw(key).and_then(|value| unescape(value, false).pop())
+    }
+
+    pub(crate) fn strings(&self, key: &str) -> Option<Vec<String>> {
+        self.raw(key).map(|value| unescape(value, true))
+    }
+
+    pub(crate) fn boolean(&self, key: &'static str) -> Result<Option<bool>, EntryError> {
+        match self.raw(key).map(String::as_str) {
+            None => Ok(None),
+            Some("true") => Ok(Some(true)),
+            Some("false") => Ok(Some(false)),
+            Some(value) => Err(EntryError::InvalidValue {
+                key,
+                value: value.to_owned(),
+            }),
+        }
+    }
+
+    /// Every localized variant of `key`, indexed by locale.
+    pub(crate) fn localized(&self, key: &str) -> HashMap<String, String> {
+        self.entries
+            .iter()
+            .filter_map(|((k, locale), value)| match locale {
+                Some(locale) if k == key => Some((locale.clone(), unescape(value, false).pop()?)),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 impl DesktopFile {
-    fn new(path: &Path) -> Result<Self, FileError> {
+    pub(crate) fn new(path: &Path) -> Result<Self, FileError> {
         let f = File::open(path).map_err(FileError::Io)?;
-        let reader = BufReader::new(f);
-        let mut lines = reader.lines();
 
-        match lines.next() {
-            Some(Ok(ref s)) if s == "[Desktop Entry]" => {}
-            _ => println!("Possibly bad desktop entry file {}", path.display()),
-        }
+        DesktopFile::from_reader(BufReader::new(f))
+    }
 
-        let mut desktop = DesktopFile(HashMap::new());
+    pub(crate) fn from_reader(reader: impl BufRead) -> Result<Self, FileError> {
+        let mut groups: Vec<(String, Group)> = vec![];
 
-        for line in lines {
+        for (number, line) in reader.lines().enumerate() {
             let line = line.map_err(FileError::Io)?;
+            let line = line.trim();
+            let bad_format = |reason| FileError::BadFormat {
+                line: number + 1,
+                reason,
+            };
 
-            if line.trim_start().starts_with('#') {
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
 
-            if line.trim_start().starts_with('[') {
-                break;
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(bad_format("unterminated group header"));
+                }
+
+                let name = &line[1..line.len() - 1];
+
+                if groups.is_empty() && name != DESKTOP_ENTRY_GROUP {
+                    return Err(bad_format("first group must be [Desktop Entry]"));
+                }
+
+                if groups.iter().any(|(group, _)| group == name) {
+                    return Err(bad_format("duplicate group"));
+                }
+
+                groups.push((
+                    name.to_owned(),
+                    Group {
+                        entries: HashMap::new(),
+                    },
+                ));
+                continue;
             }
 
-            let mut line = line.splitn(2, '=');
-            let key = line.next().ok_or(FileError::BadFormat)?.trim_end();
-            let value = line.next().ok_or(FileError::BadFormat)?.trim_start();
-            desktop.0.insert(key.to_owned(), value.to_owned());
+            let (_, group) = groups
+                .last_mut()
+                .ok_or_else(|| bad_format("key outside of any group"))?;
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim_end();
+            let value = parts
+                .next()
+                .ok_or_else(|| bad_format("expected key=value"))?
+                .trim_start();
+
+            let (key, locale) = split_locale(key).ok_or_else(|| bad_format("invalid locale"))?;
+
+            if !is_valid_key(key) {
+                return Err(bad_format("invalid key"));
+            }
+
+            let index = (key.to_owned(), locale.map(ToOwned::to_owned));
+
+            if group.entries.insert(index, value.to_owned()).is_some() {
+                return Err(bad_format("duplicate key"));
+            }
         }
 
-        Ok(desktop)
+        Ok(DesktopFile { groups })
+    }
+
+    pub(crate) fn group(&self, name: &str) -> Option<&Group> {
+        self.groups
+            .iter()
+            .find(|(group, _)| group == name)
+            .map(|(_, group)| group)
     }
 }
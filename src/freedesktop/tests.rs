@@ -0,0 +1,265 @@
+use super::*;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/freedesktop/fixtures")
+        .join(name)
+}
+
+fn bad_format(contents: &str) -> (usize, &'static str) {
+    match DesktopFile::from_reader(contents.as_bytes()) {
+        Err(FileError::BadFormat { line, reason }) => (line, reason),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("{:?} should not parse", contents),
+    }
+}
+
+fn args(exec: &str) -> Result<Vec<String>, ExecError> {
+    let icon = "icon".to_owned();
+    let command = Command::parse(exec, "Name", Some(&icon), Path::new("/a.desktop"))?;
+
+    Ok(std::iter::once(command.program)
+        .chain(command.args)
+        .collect())
+}
+
+#[test]
+fn parses_groups() {
+    let desktop = DesktopFile::new(&fixture("plasma.desktop")).unwrap();
+    let group = desktop.group("Desktop Entry").unwrap();
+
+    assert_eq!(group.string("Name").unwrap(), "Plasma Workspace");
+    assert_eq!(group.string("Comment").unwrap(), "Line one\nLine two");
+    assert_eq!(
+        group.strings("DesktopNames").unwrap(),
+        vec!["KDE", "Plasma;Wayland"]
+    );
+    assert!(group.string("Path").is_none());
+
+    let names = group.localized("Name");
+    assert_eq!(names.len(), 2);
+    assert_eq!(names["de"], "Plasma-Arbeitsbereich");
+    assert_eq!(names["sr@latin"], "Plasma radni prostor");
+
+    let action = desktop.group("Desktop Action safe").unwrap();
+    assert_eq!(action.string("Exec").unwrap(), "startplasma-x11 --safe");
+    assert!(desktop.group("Desktop Action missing").is_none());
+}
+
+#[test]
+fn parses_session_entry() {
+    let entry = Entry::parse(&fixture("plasma.desktop")).unwrap();
+
+    assert_eq!(entry.typ, EntryType::Application);
+    assert_eq!(entry.name, "Plasma Workspace");
+    assert_eq!(entry.comment.as_ref().unwrap(), "Line one\nLine two");
+    assert_eq!(entry.icon.as_ref().unwrap(), "plasma");
+    assert_eq!(entry.desktop_names, vec!["KDE", "Plasma;Wayland"]);
+}
+
+#[test]
+fn parses_actions() {
+    let entry = Entry::parse(&fixture("plasma.desktop")).unwrap();
+
+    assert_eq!(
+        entry.actions.len(),
+        1,
+        "actions without a group are skipped"
+    );
+    assert_eq!(entry.actions[0].id, "safe");
+    assert_eq!(entry.actions[0].name, "Safe mode");
+    assert_eq!(
+        entry.actions[0].exec.as_ref().unwrap(),
+        "startplasma-x11 --safe"
+    );
+    assert!(entry.actions[0].icon.is_none());
+}
+
+#[test]
+fn parses_booleans() {
+    let desktop =
+        DesktopFile::from_reader("[Desktop Entry]\nA=true\nB=false\nC=yes".as_bytes()).unwrap();
+    let group = desktop.group("Desktop Entry").unwrap();
+
+    assert_eq!(group.boolean("A").unwrap(), Some(true));
+    assert_eq!(group.boolean("B").unwrap(), Some(false));
+    assert_eq!(group.boolean("D").unwrap(), None);
+
+    match group.boolean("C") {
+        Err(EntryError::InvalidValue { key, value }) => {
+            assert_eq!(key, "C");
+            assert_eq!(value, "yes");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn unescapes_values() {
+    assert_eq!(
+        unescape(r"a\sb\nc\td\re\\f", false),
+        vec!["a b\nc\td\re\\f"]
+    );
+    assert_eq!(unescape(r"a\;b", false), vec![r"a\;b"]);
+    assert_eq!(unescape(r"a\xb\", false), vec![r"a\xb\"]);
+    assert_eq!(unescape(r"a;b\;c;;d", true), vec!["a", "b;c", "", "d"]);
+    assert_eq!(unescape("a;b;", true), vec!["a", "b"]);
+    assert_eq!(unescape("", true), Vec::<String>::new());
+}
+
+#[test]
+fn reports_bad_format_lines() {
+    assert_eq!(
+        bad_format("[Desktop Entry"),
+        (1, "unterminated group header")
+    );
+    assert_eq!(
+        bad_format("# comment\n[Other]"),
+        (2, "first group must be [Desktop Entry]")
+    );
+    assert_eq!(
+        bad_format("[Desktop Entry]\nName=a\n\n[Desktop Entry]"),
+        (4, "duplicate group")
+    );
+    assert_eq!(bad_format("\nName=a"), (2, "key outside of any group"));
+    assert_eq!(
+        bad_format("[Desktop Entry]\nName"),
+        (2, "expected key=value")
+    );
+    assert_eq!(
+        bad_format("[Desktop Entry]\nName[de=a"),
+        (2, "invalid locale")
+    );
+    assert_eq!(
+        bad_format("[Desktop Entry]\nName[]=a"),
+        (2, "invalid locale")
+    );
+    assert_eq!(bad_format("[Desktop Entry]\nNa_me=a"), (2, "invalid key"));
+    assert_eq!(
+        bad_format("[Desktop Entry]\nName=a\n# comment\nName = b"),
+        (4, "duplicate key")
+    );
+}
+
+#[test]
+fn excludes_entries() {
+    let entry = Entry::parse(&fixture("plasma.desktop")).unwrap();
+
+    assert_eq!(entry.try_exec.as_ref().unwrap(), "startplasma-x11");
+    assert!(!entry.hidden);
+    assert!(!entry.no_display);
+    assert_eq!(entry.only_show_in, vec!["KDE"]);
+    assert!(entry.not_show_in.is_empty());
+    assert_eq!(
+        entry.exclusion_reason(&["GNOME"]).unwrap(),
+        "it is only shown in KDE"
+    );
+
+    let hidden = Entry {
+        hidden: true,
+        ..entry.clone()
+    };
+    assert_eq!(
+        hidden.exclusion_reason(&["KDE"]).unwrap(),
+        "it is marked Hidden"
+    );
+
+    let not_shown = Entry {
+        only_show_in: vec![],
+        not_show_in: vec!["GNOME".to_owned()],
+        ..entry
+    };
+    assert_eq!(
+        not_shown.exclusion_reason(&["GNOME"]).unwrap(),
+        "it is not shown in GNOME"
+    );
+}
+
+#[test]
+fn rejects_invalid_booleans() {
+    match Entry::parse(&fixture("invalid-boolean.desktop")) {
+        Err(EntryError::InvalidValue { key, value }) => {
+            assert_eq!(key, "Hidden");
+            assert_eq!(value, "yes");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn parses_exec() {
+    let entry = Entry::parse(&fixture("plasma.desktop")).unwrap();
+    let exec = entry.exec.unwrap();
+
+    assert_eq!(exec.program, "/usr/bin/start plasma");
+    assert_eq!(
+        exec.args,
+        vec!["--name", "Plasma Workspace", "--file", "--percent", "100%"]
+    );
+
+    let entry = Entry::parse(&fixture("no-exec.desktop")).unwrap();
+    assert!(entry.exec.is_none());
+    assert_eq!(entry.exclusion_reason(&[]).unwrap(), "it has no Exec line");
+}
+
+#[test]
+fn tokenizes_quoted_arguments() {
+    assert_eq!(
+        tokenize(r#"a  "b c" pre"fix" "\"\`\$\\""#).unwrap(),
+        vec!["a", "b c", "prefix", r#""`$\"#]
+    );
+    assert_eq!(tokenize(" \t").unwrap(), Vec::<String>::new());
+    assert_eq!(
+        tokenize(r#"a "b"#).unwrap_err(),
+        ExecError::UnterminatedQuote
+    );
+    assert_eq!(tokenize(r#""\"#).unwrap_err(), ExecError::UnterminatedQuote);
+    assert_eq!(
+        tokenize(r#""\a""#).unwrap_err(),
+        ExecError::InvalidEscape('a')
+    );
+}
+
+#[test]
+fn expands_field_codes() {
+    assert_eq!(args("run %f %F %u %U").unwrap(), vec!["run"]);
+    assert_eq!(args("run --file=%f").unwrap(), vec!["run", "--file="]);
+    assert_eq!(args("run 100%% %c").unwrap(), vec!["run", "100%", "Name"]);
+    assert_eq!(args("run %i").unwrap(), vec!["run", "--icon", "icon"]);
+    assert_eq!(args("run %k").unwrap(), vec!["run", "/a.desktop"]);
+    assert_eq!(args("run --x%d%D%n%N%v%m").unwrap(), vec!["run", "--x"]);
+    assert_eq!(
+        args("run %z").unwrap_err(),
+        ExecError::UnknownFieldCode('z')
+    );
+    assert_eq!(args("run 100%").unwrap_err(), ExecError::TrailingPercent);
+    assert_eq!(args("%f %U").unwrap_err(), ExecError::Empty);
+}
+
+#[test]
+fn quotes_command_for_shell() {
+    let command = Command::parse(
+        r#""/usr/bin/start plasma" --name %c"#,
+        "It's mine",
+        None,
+        Path::new("/a.desktop"),
+    )
+    .unwrap();
+
+    assert_eq!(
+        command.to_shell(),
+        r#"'/usr/bin/start plasma' '--name' 'It'\''s mine'"#
+    );
+}
+
+#[test]
+fn looks_up_localized_keys() {
+    let entry = Entry::parse(&fixture("plasma.desktop")).unwrap();
+
+    assert_eq!(entry.name_in(None), "Plasma Workspace");
+    assert_eq!(entry.name_in(Some("de_DE.UTF-8")), "Plasma-Arbeitsbereich");
+    assert_eq!(entry.name_in(Some("sr_RS@latin")), "Plasma radni prostor");
+    assert_eq!(entry.name_in(Some("sr_RS")), "Plasma Workspace");
+    assert_eq!(entry.comment_in(Some("de_DE@euro")), Some("Erste Zeile"));
+    assert_eq!(entry.comment_in(Some("de_AT")), Some("Line one\nLine two"));
+}
@@ -0,0 +1,286 @@
+use crate::parameterization;
+use crate::safe;
+use crate::xauth;
+use crate::ProgramError;
+
+use const_c_str::c_str;
+use log::{info, warn};
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use serde::{Deserialize, Serialize};
+use users::os::unix::UserExt;
+
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+
+/// Syscalls the greeter has no business making, denied by the optional seccomp filter.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+/// How the greeter process failed, sent back to the main process.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum GreeterError {
+    /// The sandbox could not be set up, so the theme was never loaded
+    Sandbox(String),
+    Failed(String),
+}
+
+impl From<String> for GreeterError {
+    fn from(msg: String) -> Self {
+        GreeterError::Failed(msg)
+    }
+}
+
+impl From<GreeterError> for ProgramError {
+    fn from(err: GreeterError) -> Self {
+        match err {
+            GreeterError::Sandbox(msg) => ProgramError::Sandbox(msg),
+            GreeterError::Failed(msg) => ProgramError::GenericError(msg),
+        }
+    }
+}
+
+/// The unprivileged account the greeter process runs as.
+pub(crate) struct GreeterUser {
+    name: CString,
+    uid: u32,
+    gid: u32,
+    groups: Vec<libc::gid_t>,
+    home: CString,
+    xauthority: Option<CString>,
+}
+
+fn to_cstring(value: impl Into<Vec<u8>>, what: &str) -> Result<CString, ProgramError> {
+    CString::new(value).map_err(|_| {
+        ProgramError::GenericError(format!("{} should not contain any nul bytes", what))
+    })
+}
+
+impl GreeterUser {
+    /// Looks up the configured greeter user and hands it a copy of the x server cookie. Must run
+    /// in the privileged process.
+    pub(crate) fn prepare(
+        config: &parameterization::Greeter,
+        xorg: &parameterization::XOrgConfig,
+        cookie: Option<&xauth::Cookie>,
+    ) -> Result<Option<Self>, ProgramError> {
+        let name = match config.user {
+            Some(ref name) => name,
+            None => {
+                warn!("No greeter user configured, the greeter will run as root");
+                return Ok(None);
+            }
+        };
+
+        let user = users::get_user_by_name(name).ok_or_else(|| {
+            ProgramError::GenericError(format!("Greeter user '{}' does not exist", name))
+        })?;
+
+        let xauthority = match cookie {
+            Some(cookie) => {
+                let path = Path::new(&xorg.runtime_dir).join(format!(
+                    "greeter-{}.auth",
+                    xorg.display.trim_start_matches(':')
+                ));
+
+                info!("Writing greeter authority to {}", path.display());
+                xauth::write(
+                    &path,
+                    &xorg.display,
+                    cookie,
+                    Some((user.uid(), user.primary_group_id())),
+                )
+                .map_err(ProgramError::Io)?;
+
+                Some(to_cstring(
+                    path.to_string_lossy().into_owned(),
+                    "Authority path",
+                )?)
+            }
+            None => None,
+        };
+
+        let name = to_cstring(name.as_bytes(), "Greeter user name")?;
+        let groups = safe::libc::user_groups(&name, user.primary_group_id()).map_err(|e| {
+            ProgramError::GenericError(format!("Could not look up greeter groups: {}", e))
+        })?;
+
+        Ok(Some(GreeterUser {
+            name,
+            uid: user.uid(),
+            gid: user.primary_group_id(),
+            groups,
+            home: to_cstring(
+                user.home_dir().to_string_lossy().into_owned(),
+                "Greeter home directory",
+            )?,
+            xauthority,
+        }))
+    }
+
+    /// Permanently switches the calling process to the greeter user. Meant to be called in the
+    /// greeter process before GTK is initialized.
+    pub(crate) fn become_greeter(&self) -> Result<(), String> {
+        safe::libc::become_user(&self.groups, self.uid, self.gid)
+            .map_err(|e| format!("Could not switch to greeter user: {}", e))?;
+
+        if nix::unistd::setuid(nix::unistd::Uid::from_raw(0)).is_ok() {
+            return Err("Greeter process could regain root privileges".into());
+        }
+
+        let setenv = |name: &'static CStr, value: &CStr| {
+            safe::libc::setenv(name, value)
+                .map_err(|_| format!("Could not set {:?} for the greeter", name))
+        };
+
+        setenv(c_str!("HOME"), self.home.as_c_str())?;
+        setenv(c_str!("USER"), self.name.as_c_str())?;
+        setenv(c_str!("LOGNAME"), self.name.as_c_str())?;
+
+        if let Some(ref xauthority) = self.xauthority {
+            setenv(c_str!("XAUTHORITY"), xauthority.as_c_str())?;
+        }
+
+        nix::unistd::chdir(self.home.as_c_str())
+            .map_err(|e| format!("Could not change to greeter home directory: {}", e))
+    }
+}
+
+/// Restrictions applied to the greeter process on top of running as the greeter user.
+pub(crate) struct Sandbox {
+    theme: PathBuf,
+    network: bool,
+    seccomp: bool,
+}
+
+impl Sandbox {
+    /// Sandboxing is refused without a greeter user, as the greeter would keep running as root.
+    pub(crate) fn prepare(
+        config: &parameterization::Sandbox,
+        theme: &parameterization::Theme,
+        user: Option<&GreeterUser>,
+    ) -> Result<Option<Self>, ProgramError> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        if user.is_none() {
+            return Err(ProgramError::Sandbox(
+                "The greeter sandbox needs a greeter user to be configured".into(),
+            ));
+        }
+
+        let theme_path = std::fs::canonicalize(&theme.path).map_err(|e| {
+            ProgramError::Sandbox(format!(
+                "Could not resolve theme path {}: {}",
+                theme.path, e
+            ))
+        })?;
+
+        Ok(Some(Sandbox {
+            theme: theme_path,
+            // Only themes allowed to load external resources need the network, everything else
+            // is served through the theme scheme and the X server is reachable through its socket
+            network: theme.allow_external_resources,
+            seccomp: config.seccomp,
+        }))
+    }
+
+    /// Moves the calling process into its own mount and network namespaces, with the theme
+    /// mounted read-only. Needs root.
+    fn isolate(&self) -> Result<(), String> {
+        let mut flags = CloneFlags::CLONE_NEWNS;
+
+        if !self.network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+
+        unshare(flags).map_err(|e| format!("Could not create namespaces: {}", e))?;
+
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .map_err(|e| format!("Could not make mounts private: {}", e))?;
+
+        mount(
+            Some(self.theme.as_path()),
+            self.theme.as_path(),
+            None::<&str>,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None::<&str>,
+        )
+        .map_err(|e| format!("Could not bind mount theme: {}", e))?;
+
+        mount(
+            None::<&str>,
+            self.theme.as_path(),
+            None::<&str>,
+            MsFlags::MS_BIND
+                | MsFlags::MS_REMOUNT
+                | MsFlags::MS_RDONLY
+                | MsFlags::MS_NOSUID
+                | MsFlags::MS_NODEV,
+            None::<&str>,
+        )
+        .map_err(|e| format!("Could not remount theme read-only: {}", e))
+    }
+
+    fn restrict(&self) -> Result<(), String> {
+        safe::libc::set_no_new_privs()
+            .map_err(|e| format!("Could not set PR_SET_NO_NEW_PRIVS: {}", e))?;
+
+        if self.seccomp {
+            safe::libc::deny_syscalls(DENIED_SYSCALLS)
+                .map_err(|e| format!("Could not install seccomp filter: {}", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets up the greeter process before GTK is initialized. Any failure aborts the greeter.
+pub(crate) fn enter(
+    user: Option<&GreeterUser>,
+    sandbox: Option<&Sandbox>,
+) -> Result<(), GreeterError> {
+    if let Some(sandbox) = sandbox {
+        sandbox.isolate().map_err(GreeterError::Sandbox)?;
+    }
+
+    if let Some(user) = user {
+        user.become_greeter()?;
+    }
+
+    if let Some(sandbox) = sandbox {
+        sandbox.restrict().map_err(GreeterError::Sandbox)?;
+    }
+
+    Ok(())
+}
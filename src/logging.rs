@@ -0,0 +1,387 @@
+use crate::parameterization::{self, LogLevel, LogOutput};
+
+use const_c_str::c_str;
+use log::{Level, LevelFilter, Metadata, Record};
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CRATE_PREFIX: &str = "webdm::";
+/// The greeter cannot rotate the log file, so a chatty theme is only heard when asked for
+const THEME_MODULE: &str = "webdm::theme";
+const THEME_DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+/// Index into `PROCESSES` of the process this is, inherited by forked children.
+static PROCESS: AtomicUsize = AtomicUsize::new(0);
+const PROCESSES: &[&str] = &["main", "greeter"];
+
+#[derive(Clone, Copy)]
+pub(crate) enum Process {
+    Main = 0,
+    Greeter = 1,
+}
+
+/// Tags further lines of this process with `process`. Only the main process rotates log files,
+/// as child processes may lack the permissions to do so. They follow the rotation by reopening
+/// the file instead.
+pub(crate) fn set_process(process: Process) {
+    PROCESS.store(process as usize, Ordering::SeqCst);
+}
+
+fn process() -> &'static str {
+    PROCESSES[PROCESS.load(Ordering::SeqCst)]
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    }
+}
+
+fn syslog_priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+fn timestamp() -> String {
+    let mut buf = [0u8; 32];
+
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            c_str!("%Y-%m-%d %H:%M:%S").as_ptr(),
+            &tm,
+        )
+    };
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+enum Output {
+    Stderr,
+    File {
+        file: File,
+        path: PathBuf,
+        max_size: u64,
+        keep: u32,
+    },
+    Syslog,
+}
+
+impl Output {
+    fn open(config: &parameterization::Log) -> std::io::Result<Self> {
+        match config.output {
+            LogOutput::Stderr => Ok(Output::Stderr),
+            LogOutput::File => {
+                let path = PathBuf::from(&config.path);
+
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                Ok(Output::File {
+                    file: OpenOptions::new().create(true).append(true).open(&path)?,
+                    path,
+                    max_size: config.max_size,
+                    keep: config.keep,
+                })
+            }
+            LogOutput::Syslog => {
+                unsafe { libc::openlog(c_str!("webdm").as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+                Ok(Output::Syslog)
+            }
+        }
+    }
+
+    /// Moves `path` to `path.1`, `path.1` to `path.2` and so on, dropping the oldest file.
+    fn rotate(path: &Path, keep: u32) -> std::io::Result<File> {
+        let numbered = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+
+        if keep == 0 {
+            std::fs::remove_file(path).ok();
+        } else {
+            std::fs::remove_file(numbered(keep)).ok();
+
+            for n in (1..keep).rev() {
+                std::fs::rename(numbered(n), numbered(n + 1)).ok();
+            }
+
+            std::fs::rename(path, numbered(1))?;
+        }
+
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Opens `path` again if it no longer is `file`, as after the main process rotated it. Lines
+    /// keep going to `file` if `path` cannot be opened.
+    fn reopen(file: &File, path: &Path) -> Option<File> {
+        let current = std::fs::metadata(path).ok()?;
+        let open = file.metadata().ok()?;
+
+        if current.dev() == open.dev() && current.ino() == open.ino() {
+            return None;
+        }
+
+        OpenOptions::new().append(true).open(path).ok()
+    }
+}
+
+struct Inner {
+    level: LevelFilter,
+    /// Per module levels, most specific module first
+    modules: Vec<(String, LevelFilter)>,
+    output: Output,
+}
+
+impl Inner {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module.as_str() || target.starts_with(&format!("{}::", module))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, std::cmp::max)
+    }
+
+    fn write(&mut self, record: &Record) {
+        let target = record.target();
+        let target = if target.starts_with(CRATE_PREFIX) {
+            &target[CRATE_PREFIX.len()..]
+        } else {
+            target
+        };
+        let pid = std::process::id();
+
+        match self.output {
+            Output::Stderr => {
+                eprintln!(
+                    "{} {:5} {}[{}] {}: {}",
+                    timestamp(),
+                    record.level(),
+                    process(),
+                    pid,
+                    target,
+                    record.args()
+                );
+            }
+            Output::File {
+                ref mut file,
+                ref path,
+                max_size,
+                keep,
+            } => {
+                let line = format!(
+                    "{} {:5} {}[{}] {}: {}\n",
+                    timestamp(),
+                    record.level(),
+                    process(),
+                    pid,
+                    target,
+                    record.args()
+                );
+
+                let main = PROCESS.load(Ordering::SeqCst) == Process::Main as usize;
+
+                if main {
+                    let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+
+                    if size + line.len() as u64 > max_size {
+                        match Output::rotate(path, keep) {
+                            Ok(rotated) => *file = rotated,
+                            Err(e) => eprintln!("Could not rotate {}: {}", path.display(), e),
+                        }
+                    }
+                } else if let Some(reopened) = Output::reopen(file, path) {
+                    *file = reopened;
+                }
+
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    eprintln!("Could not write to {}: {}", path.display(), e);
+                }
+            }
+            Output::Syslog => {
+                let message = format!("{} {}: {}", process(), target, record.args());
+
+                if let Ok(message) = CString::new(message) {
+                    unsafe {
+                        libc::syslog(
+                            syslog_priority(record.level()),
+                            c_str!("%s").as_ptr(),
+                            message.as_ptr(),
+                        )
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Writes lines tagged with level, process and module. The greeter shares the logger with the
+/// main process after forking.
+#[derive(Clone)]
+pub(crate) struct Logger(Arc<Mutex<Inner>>);
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let inner = self.0.lock().expect("Logger mutex to be un-poisoned");
+        metadata.level() <= inner.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        let mut inner = self.0.lock().expect("Logger mutex to be un-poisoned");
+
+        if record.level() <= inner.level_for(record.target()) {
+            inner.write(record);
+        }
+    }
+
+    fn flush(&self) {
+        let mut inner = self.0.lock().expect("Logger mutex to be un-poisoned");
+
+        if let Output::File { ref mut file, .. } = inner.output {
+            file.flush().ok();
+        }
+    }
+}
+
+fn modules(config: &parameterization::Log) -> Vec<(String, LevelFilter)> {
+    let mut modules: Vec<_> = config
+        .modules
+        .iter()
+        .map(|(module, level)| {
+            let module = if module.starts_with(CRATE_PREFIX) {
+                module.clone()
+            } else {
+                format!("{}{}", CRATE_PREFIX, module)
+            };
+
+            (module, level_filter(*level))
+        })
+        .collect();
+
+    if modules
+        .iter()
+        .all(|(module, _)| module.as_str() != THEME_MODULE)
+    {
+        let level = std::cmp::min(level_filter(config.level), THEME_DEFAULT_LEVEL);
+        modules.push((THEME_MODULE.to_owned(), level));
+    }
+
+    modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+    modules
+}
+
+impl Logger {
+    /// Installs the logger for the whole process.
+    pub(crate) fn init(config: &parameterization::Log) -> std::io::Result<Self> {
+        let inner = Inner {
+            level: level_filter(config.level),
+            modules: modules(config),
+            output: Output::open(config)?,
+        };
+
+        log::set_max_level(inner.max_level());
+
+        let logger = Logger(Arc::new(Mutex::new(inner)));
+        log::set_boxed_logger(Box::new(logger.clone()))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+
+        Ok(logger)
+    }
+
+    /// Applies a reloaded configuration.
+    pub(crate) fn reconfigure(&self, config: &parameterization::Log) -> std::io::Result<()> {
+        let output = Output::open(config)?;
+        let mut inner = self.0.lock().expect("Logger mutex to be un-poisoned");
+
+        inner.level = level_filter(config.level);
+        inner.modules = modules(config);
+        inner.output = output;
+        log::set_max_level(inner.max_level());
+
+        Ok(())
+    }
+}
+
+/// Escapes control characters such as newlines, so text from untrusted sources cannot forge or
+/// garble log lines.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+/// Limits how many messages a noisy source may log per window.
+pub(crate) struct RateLimit {
+    max: u32,
+    window: Duration,
+    /// Start of the current window, messages admitted in it and messages dropped since the last
+    /// admitted one
+    state: Cell<(Instant, u32, u32)>,
+}
+
+impl RateLimit {
+    pub(crate) fn new(max: u32, window: Duration) -> Self {
+        RateLimit {
+            max,
+            window,
+            state: Cell::new((Instant::now(), 0, 0)),
+        }
+    }
+
+    /// Whether another message may be logged, along with how many were dropped before it.
+    pub(crate) fn admit(&self) -> Option<u32> {
+        let (start, count, dropped) = self.state.get();
+        let now = Instant::now();
+
+        let (start, count) = if now.duration_since(start) >= self.window {
+            (now, 0)
+        } else {
+            (start, count)
+        };
+
+        if count >= self.max {
+            self.state.set((start, count, dropped + 1));
+            None
+        } else {
+            self.state.set((start, count + 1, 0));
+            Some(dropped)
+        }
+    }
+}
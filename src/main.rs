@@ -3,18 +3,26 @@
 mod convenience;
 mod auth;
 mod freedesktop;
+mod greeter;
+mod logging;
 mod message;
 mod parameterization;
+mod power;
 mod safe;
+mod session;
+mod signals;
+mod state;
 mod webkit;
+mod xauth;
 
 use parameterization::Config;
-use webkit::{UserContentManagerHelpers, WebViewHelpers};
+use webkit::{UserContentManagerHelpers, WebContextHelpers, WebViewHelpers};
 
 use clap::App;
 use const_c_str::c_str;
 use gdk::{Cursor, CursorType, ScreenExt, WindowExt};
 use gtk::{ContainerExt, Continue, GtkWindowExt, Inhibit, WidgetExt, Window, WindowType};
+use log::{debug, error, info, log, log_enabled, warn, Level};
 use pam::Converse;
 use webkit2gtk::{
     ContextMenuExt, SettingsExt, UserContentManager, WebContext, WebInspectorExt, WebView,
@@ -24,81 +32,278 @@ use webkit2gtk::{
 use users;
 use users::os::unix::UserExt;
 
-use nix::unistd::{chdir, gethostname};
+use nix::unistd::{chdir, gethostname, Pid};
 
 use std::cell::Cell;
-use std::collections::HashMap;
 use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
 use std::os::unix::process::CommandExt;
 use std::rc::Rc;
-use std::sync::Mutex;
+
+const THEME_SCHEME: &str = "webdm";
+const THEME_HOST: &str = "theme";
+/// Log target of messages from the theme, so their level can be configured separately
+const THEME_LOG_TARGET: &str = "webdm::theme";
+/// Messages a theme may log every 10 seconds, as only the main process rotates the log file
+const THEME_LOG_LIMIT: u32 = 50;
 
 #[derive(Debug)]
 enum ProgramError {
     Config(parameterization::ConfigError),
     Io(std::io::Error),
     GenericError(String),
+    Sandbox(String),
+    /// The greeter process died without returning a session, e.g. after a crash in WebKit
+    GreeterCrashed(safe::libc::WaitError),
+    Terminated,
     XServerQuit,
 }
 
-const JS_NUMBER_MASK: u64 = (1 << 53) - 1;
-
 fn kill_x<'a, T>(x: &'a mut Option<std::process::Child>) -> impl FnOnce(T) -> T + 'a {
     |e| {
         for x in x {
-            println!("Killing x server...");
+            debug!("Killing x server...");
             x.kill().ok();
             x.wait().expect("X server to cleanly exit");
-            println!("Done");
+            debug!("Done");
         }
         e
     }
 }
 
-fn main() -> Result<(), ProgramError> {
-    let app = parameterization::WebDMApp::from(
+fn app<'a, 'b>() -> parameterization::WebDMApp<'a, 'b> {
+    parameterization::WebDMApp::from(
         App::new("WebDM")
             .version(clap::crate_version!())
             .author(clap::crate_authors!())
             .about(clap::crate_description!()),
-    );
+    )
+}
+
+fn main() -> Result<(), ProgramError> {
+    let signals = signals::Signals::install().map_err(|e| {
+        ProgramError::GenericError(format!("Could not set up signal handling: {}", e))
+    })?;
+
+    let mut config = Config::from(app())?;
+    let logger = logging::Logger::init(&config.log).map_err(ProgramError::Io)?;
+    let mut failures = 0;
+
+    loop {
+        let restart = config.restart.clone();
+        let result = run(config.clone(), &signals);
+
+        if signals.terminating() {
+            match result {
+                Ok(_) | Err(ProgramError::Terminated) => {}
+                Err(e) => error!("Error during shutdown: {:?}", e),
+            }
+
+            info!("Terminated");
+            return Ok(());
+        }
 
-    let config = Config::from(app)?;
+        let failed = match result {
+            Ok(status) if status.success() => false,
+            Ok(status) => {
+                warn!("Session exited unsuccessfully: {}", status);
+                true
+            }
+            Err(ProgramError::GreeterCrashed(ref e)) => {
+                error!("Greeter process {}", e);
+                true
+            }
+            Err(ref e) => {
+                error!("Greeter or session failed: {:?}", e);
+                true
+            }
+        };
+
+        let again = match (restart.policy, &result) {
+            // No session was started, so the greeter is always brought back
+            (_, Err(ProgramError::GreeterCrashed(_))) => true,
+            (parameterization::RestartPolicy::Never, _) => false,
+            (parameterization::RestartPolicy::Session, Err(_)) => false,
+            _ => true,
+        };
+
+        if !again {
+            info!("Finished");
+            return result.map(|_| ());
+        }
+
+        if failed {
+            failures += 1;
+
+            if failures > restart.max_failures {
+                return Err(ProgramError::GenericError(format!(
+                    "Giving up after {} consecutive failures",
+                    failures
+                )));
+            }
+
+            let backoff = restart
+                .backoff
+                .saturating_mul(1 << (failures - 1).min(6))
+                .min(restart.max_backoff);
 
+            info!("Restarting greeter in {} seconds", backoff);
+            if !signals.sleep(std::time::Duration::from_secs(backoff)) {
+                info!("Terminated");
+                return Ok(());
+            }
+        } else {
+            failures = 0;
+        }
+
+        if signals.take_reload() {
+            match Config::from(app()) {
+                Ok(reloaded) => {
+                    if let Err(e) = logger.reconfigure(&reloaded.log) {
+                        warn!(
+                            "Could not reconfigure logging, keeping the old output: {}",
+                            e
+                        );
+                    }
+
+                    info!("Configuration reloaded");
+                    config = reloaded;
+                }
+                Err(e) => warn!(
+                    "Could not reload configuration, keeping the old one: {:?}",
+                    e
+                ),
+            }
+        }
+
+        // Only log in automatically when the machine boots, not every time the user logs out
+        config.autologin = None;
+
+        info!("Returning to greeter");
+    }
+}
+
+fn run(
+    config: Config,
+    signals: &signals::Signals,
+) -> Result<std::process::ExitStatus, ProgramError> {
     let display_cstr = CString::new(config.xorg.display.clone())
         .expect("Display string should not contain any nul bytes");
 
-    let mut x = if config.create_x_server {
-        println!("Creating x server");
-        let mut x = safe::x11::start_x_server(&config.xorg.display, &config.xorg.vt)
+    let (mut x, cookie) = if config.create_x_server {
+        let cookie = xauth::Cookie::generate().map_err(ProgramError::Io)?;
+        let runtime_dir = std::path::Path::new(&config.xorg.runtime_dir);
+        let server_auth = runtime_dir.join(format!(
+            "{}.auth",
+            config.xorg.display.trim_start_matches(':')
+        ));
+
+        info!("Writing x server authority to {}", server_auth.display());
+        xauth::create_runtime_dir(runtime_dir).map_err(ProgramError::Io)?;
+        xauth::write(&server_auth, &config.xorg.display, &cookie, None)
             .map_err(ProgramError::Io)?;
 
-        while !safe::x11::poll_for_x_available(&mut x, display_cstr.as_c_str())? {}
-        Some(x)
+        let server_auth_cstr = CString::new(server_auth.to_string_lossy().into_owned())
+            .expect("Authority path should not contain any nul bytes");
+        safe::libc::setenv(c_str!("XAUTHORITY"), server_auth_cstr.as_c_str())?;
+
+        info!("Creating x server");
+        let mut x = safe::x11::start_x_server(&config.xorg.display, &config.xorg.vt, &server_auth)
+            .map_err(ProgramError::Io)?;
+
+        while !safe::x11::poll_for_x_available(&mut x, display_cstr.as_c_str())? {
+            if !signals.sleep(std::time::Duration::from_millis(100)) {
+                kill_x(&mut Some(x))(());
+                return Err(ProgramError::Terminated);
+            }
+        }
+        (Some(x), Some(cookie))
     } else {
-        None
+        (None, None)
     };
 
-    println!("Setting DISPLAY env to {:#?}", config.xorg.display);
+    debug!("Setting DISPLAY env to {:#?}", config.xorg.display);
     safe::libc::setenv(c_str!("DISPLAY"), display_cstr.as_c_str()).map_err(kill_x(&mut x))?;
 
-    println!("Starting login greeter");
-    let (mut wm, pam) = webkit(config).map_err(kill_x(&mut x))?;
+    let state_path = std::path::PathBuf::from(&config.state.path);
+    let session_config = config.session.clone();
+
+    info!("Starting login greeter");
+    let (session, mut authenticator) = webkit(config, cookie, signals).map_err(kill_x(&mut x))?;
 
-    println!("X Session started");
+    if signals.terminating() {
+        kill_x(&mut x)(());
+        return Err(ProgramError::Terminated);
+    }
 
-    wm.wait()
+    let mut state = state::State::load(&state_path);
+    state.remember(authenticator.pam().handler().username(), &session.id);
+    if let Err(e) = state.save(&state_path) {
+        warn!("Could not save state to {}: {}", state_path.display(), e);
+    }
+
+    if session.typ == session::SessionType::Wayland {
+        info!("Wayland session chosen, stopping x server");
+        kill_x(&mut x)(());
+        x = None;
+    }
+
+    let (mut wm, logger) =
+        spawn_session(&mut authenticator, &session, &session_config).map_err(kill_x(&mut x))?;
+    let pam = authenticator.into_pam();
+
+    info!("Session started");
+    signals.set_session(Some(Pid::from_raw(wm.id() as i32)));
+
+    let exited = safe::libc::wait_exited(Pid::from_raw(wm.id() as i32));
+    signals.set_session(None);
+
+    if let Err(e) = exited {
+        warn!("Could not wait for session without reaping it: {}", e);
+    }
+
+    let status = wm
+        .wait()
         .map_err(|e| {
-            ProgramError::GenericError(format!("Error while waiting for x session to stop: {}", e))
+            ProgramError::GenericError(format!("Error while waiting for session to stop: {}", e))
         })
         .map_err(kill_x(&mut x))?;
 
+    info!("Session exited, closing PAM session");
     drop(pam);
 
-    println!("X Session exited");
     kill_x(&mut x)(());
-    println!("Finished");
-    Ok(())
+
+    if let Some(logger) = logger {
+        reap_logger(logger);
+    }
+
+    Ok(status)
+}
+
+/// Reaps the session logger, which stops once nothing holds the session output open anymore.
+/// Processes the session left behind may keep it open, so it is killed after a short while.
+fn reap_logger(mut logger: std::process::Child) {
+    for _ in 0..10 {
+        match logger.try_wait() {
+            Ok(Some(_)) => return,
+            Ok(None) => std::thread::sleep(std::time::Duration::from_millis(100)),
+            Err(e) => {
+                warn!("Error while waiting for session logger to stop: {}", e);
+                return;
+            }
+        }
+    }
+
+    warn!("Session logger did not stop, killing it");
+    logger.kill().ok();
+
+    if let Err(e) = logger.wait() {
+        warn!("Error while waiting for session logger to stop: {}", e);
+    }
 }
 
 macro_rules! catch {
@@ -125,48 +330,139 @@ macro_rules! catch {
 
 fn webkit(
     config: Config,
-) -> Result<
-    (
-        std::process::Child,
-        pam::Authenticator<'static, pam::PasswordConv>,
-    ),
-    ProgramError,
-> {
+    cookie: Option<xauth::Cookie>,
+    signals: &signals::Signals,
+) -> Result<(session::SessionEntry, auth::Auth<'static>), ProgramError> {
     let theme_path = config.theme.path.clone();
+    let theme_name = std::path::Path::new(&theme_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| theme_path.clone());
     let display = config.xorg.display;
     let secure = !config.theme.allow_external_resources;
     let default_session_name = config.session.default;
     let session_path = config.session.path;
+    let wayland_session_path = config.session.wayland_path;
     let hide_users = config.users.hide;
     let home_prefix = config.users.home_prefix;
+    let state = state::State::load(std::path::Path::new(&config.state.path));
+    let dmrc = config.state.dmrc;
     let debug = config.theme.debug;
+    let locale = config
+        .theme
+        .language
+        .clone()
+        .or_else(freedesktop::environment_locale);
+    let autologin = config.autologin;
+
+    let greeter_user =
+        greeter::GreeterUser::prepare(&config.greeter, &config.xorg, cookie.as_ref())?;
+    let sandbox = greeter::Sandbox::prepare(
+        &config.greeter.sandbox,
+        &config.theme,
+        greeter_user.as_ref(),
+    )?;
 
-    let (callbacks, mut authenticator) = auth::Auth::create(
+    let power = power::backend(&config.power);
+    let can_hibernate = power.can(power::Action::Hibernate);
+    let can_restart = power.can(power::Action::Restart);
+    let can_shutdown = power.can(power::Action::Shutdown);
+    let can_suspend = power.can(power::Action::Suspend);
+
+    let (callbacks, answers, mut authenticator) = auth::Auth::create(
         display,
         u8::from_str_radix(config.xorg.vt.trim_start_matches("vt"), 10)
             .map_err(|e| ProgramError::GenericError(format!("Could not parse vt string: {}", e)))?,
+        autologin.as_ref().map(|autologin| autologin.user.clone()),
+        cookie,
+        power,
     )
     .map_err(|e| {
         ProgramError::GenericError(format!("Could not create PAM authenticator: {:?}", e))
     })?;
 
-    let send_auth = authenticator.sender();
+    let mut sessions = session::Sessions::discover(
+        &session_path,
+        &wayland_session_path,
+        default_session_name.as_ref(),
+        locale.as_ref().map(String::as_str),
+    );
 
-    let http_server = safe::libc::run_in_process(|| {
-        rouille::start_server("localhost:8742", move |request| {
-            let response = rouille::match_assets(&request, &theme_path);
-            if response.is_success() {
-                return response.with_no_cache();
-            }
+    if let Some(autologin) = autologin
+        .as_ref()
+        .filter(|autologin| autologin.delay.unwrap_or(0) == 0)
+    {
+        let wm = sessions
+            .named(autologin.session.as_ref())
+            .and_then(|session| sessions.entries.remove(&session.key))
+            .ok_or_else(|| {
+                ProgramError::GenericError("Could not find session for automatic login".into())
+            })?;
 
-            rouille::Response::empty_404().with_no_cache()
-        });
-    })
-    .map_err(|e| {
-        ProgramError::GenericError(format!("Could not fork to create http server: {}", e))
-    })?;
+        authenticator.autologin(None).map_err(|failure| {
+            ProgramError::GenericError(format!(
+                "Automatic login of user '{}' failed: {}",
+                autologin.user,
+                failure.message()
+            ))
+        })?;
+
+        authenticator
+            .open_session(&wm)
+            .map_err(|failure| ProgramError::GenericError(failure.message()))?;
+
+        let wm = authenticator
+            .opened_session()
+            .map_err(|failure| ProgramError::GenericError(failure.message()))?;
+
+        return Ok((wm, authenticator));
+    }
+
+    let autologin_delay = autologin
+        .as_ref()
+        .map(|autologin| autologin.delay.unwrap_or(0));
+    let autologin = autologin.map(|autologin| {
+        serde_json::json!({
+            "username": autologin.user,
+            "session": sessions.named(autologin.session.as_ref()),
+            "timeout": autologin.delay,
+        })
+    });
+
+    // Home directories are read here, as the greeter may not be allowed to
+    let users: Vec<_> = if hide_users {
+        vec![]
+    } else {
+        unsafe { users::all_users() }
+            .filter(|user| user.home_dir().starts_with(&home_prefix))
+            .map(|user| {
+                let name = user.name().to_string_lossy();
+                let last_session = state
+                    .last_session(&user, dmrc)
+                    .and_then(|id| sessions.with_id(&id).cloned());
+
+                serde_json::json!({
+                    "display_name": name,
+                    "username": name,
+                    "last_session": last_session,
+                })
+            })
+            .collect()
+    };
+
+    if let Some(delay) = autologin_delay {
+        authenticator.schedule_autologin(std::time::Duration::from_secs(delay));
+    }
+
+    let send_auth = authenticator.sender();
+    let entries = std::mem::replace(&mut sessions.entries, Default::default());
+
+    let gtk_proc = safe::libc::return_from_process::<Result<(), greeter::GreeterError>, _>(move || {
+        logging::set_process(logging::Process::Greeter);
+        signals::Signals::unblock()
+            .map_err(|e| greeter::GreeterError::Failed(format!("Could not unblock signals: {}", e)))?;
+        greeter::enter(greeter_user.as_ref(), sandbox.as_ref())?;
 
-    let gtk_proc = safe::libc::return_from_process::<Result<freedesktop::Entry, String>, _>(move || {
         convenience::maybe(move || {
             let send_auth = Rc::new(send_auth);
 
@@ -187,140 +483,102 @@ fn webkit(
 
             let callback_sym = Rc::new(convenience::hash(std::time::SystemTime::now()).to_string());
 
-            let mut default_session = None;
-            let mut sessions = vec![];
-
-            let entries: Mutex<HashMap<_, _>> = Mutex::new({
-                match std::fs::read_dir(session_path) {
-                    Err(_) => HashMap::new(),
-                    Ok(entries) => entries
-                        .into_iter()
-                        .filter_map(|entry| {
-                            entry.ok().and_then(|entry| {
-                                let key = entry.path();
-                                match freedesktop::Entry::parse(&key) {
-                                    Err(e) => {
-                                        println!(
-                                            "Error during parsing of {:#?}: {:?}",
-                                            entry.path(),
-                                            e
-                                        );
-                                        None
-                                    }
-                                    Ok(entry) => {
-                                        if entry.typ == freedesktop::EntryType::Application {
-                                            let key = convenience::hash(
-                                                key.to_string_lossy().into_owned(),
-                                            ) & JS_NUMBER_MASK;
-
-                                            let new_default = default_session_name
-                                                .as_ref()
-                                                .map(|default| default == &entry.name)
-                                                .unwrap_or(false);
-
-                                            let session = message::Session {
-                                                key,
-                                                name: entry.name.clone(),
-                                                comment: entry
-                                                    .comment
-                                                    .as_ref()
-                                                    .cloned()
-                                                    .unwrap_or_else(|| "".to_string()),
-                                            };
-
-                                            if new_default {
-                                                default_session = Some(session.clone());
-                                            };
-
-                                            sessions.push(session);
-
-                                            Some((key, entry))
-                                        } else {
-                                            None
-                                        }
-                                    }
-                                }
-                            })
-                        })
-                        .collect(),
-                }
-            });
-
-            let default_session = default_session.or_else(|| sessions.iter().next().cloned());
-
-            let users = if hide_users { None } else { Some(unsafe {users::all_users()}) };
+            // Id of the open session request, the greeter is done once it succeeded
+            let opening: Rc<Cell<Option<u64>>> = Rc::new(Cell::new(None));
 
             scripts.add_onload_script(&format!(
                 "{}({});",
                 include_str!("script.js"),
                 serde_json::json!({
-                    "can_hibernate": false,
-                    "can_restart": false,
-                    "can_shutdown": false,
-                    "can_suspend": false,
-                    "default_session": default_session,
+                    "can_hibernate": can_hibernate,
+                    "can_restart": can_restart,
+                    "can_shutdown": can_shutdown,
+                    "can_suspend": can_suspend,
+                    "autologin": autologin,
+                    "default_session": sessions.default,
                     "hide_users": hide_users,
+                    "last_user": if hide_users { None } else { state.last_user.as_ref() },
                     "hostname": gethostname(&mut [0u8; 1024]).ok(),
                     "lock_hint": false,
-                    "sessions": sessions,
-                    "users": serde_json::json!(users.map(|users| users.filter_map(|user| {
-                        if user.home_dir().starts_with(&home_prefix) {
-                            let name = user.name().to_string_lossy();
-
-                            Some(serde_json::json!({
-                                "display_name": name,
-                                "username": name,
-                            }))
-                        } else {
-                            None
-                        }
-                    }).collect()).unwrap_or_else(|| vec![])),
+                    "sessions": sessions.list,
+                    "users": users,
                     "callback_secret": *callback_sym,
                     "debug": debug,
                     "secure": secure,
                 })
             ));
 
-            gtk::idle_add(clone!(ret, webview in move || {
-                match callbacks.try_recv() {
-                    Err(e) => {
-                        if let ipc_channel::ErrorKind::Io(ref e) = *e {
-                            if let std::io::ErrorKind::WouldBlock = e.kind() {
-                                return Continue(true);
+            // Replies are received on a separate thread, which wakes the main loop through a
+            // socket so GTK only runs when there is something to do
+            let (wake_read, mut wake_write) = UnixStream::pair()
+                .map_err(|e| format!("Could not create wakeup socket: {}", e))?;
+            let (forward, replies) = std::sync::mpsc::channel();
+
+            std::thread::spawn(move || loop {
+                let reply = callbacks.recv();
+                let closed = reply.is_err();
+
+                if forward.send(reply).is_err() || wake_write.write_all(&[0]).is_err() || closed {
+                    break;
+                }
+            });
+
+            safe::glib::fd_watch(wake_read.as_raw_fd(), clone!(ret, webview, opening in move || {
+                let mut buf = [0u8; 64];
+                if let Err(e) = (&wake_read).read(&mut buf) {
+                    warn!("Could not read from wakeup socket: {}", e);
+                }
+
+                for reply in replies.try_iter() {
+                    match reply {
+                        Err(e) => {
+                            ret.set(Some(Err(
+                                format!("Attempted to read from callback channel, but failed: {}", e)
+                            )));
+
+                            gtk::main_quit();
+                            return Continue(false);
+                        },
+                        Ok(call) => match call.message {
+                            auth::Reply::Done(result) => {
+                                let opened = opening.get() == Some(call.id) && result.is_ok();
+
+                                webview.respond(
+                                    &callback_sym,
+                                    call.id,
+                                    serde_json::json!({
+                                        "ok": result.is_ok(),
+                                        "error": result.err().map(|failure| failure.to_json()),
+                                    }),
+                                );
+
+                                if opened {
+                                    ret.set(Some(Ok(())));
+                                    gtk::main_quit();
+                                    return Continue(false);
+                                }
+                            }
+                            auth::Reply::Prompt(prompt) => {
+                                webview.notify(&callback_sym, call.id, prompt.to_json())
                             }
-                        }
-
-                        ret.set(Some(Err(
-                            format!("Attempted to read from callback channel, but failed: {}", e)
-                        )));
-
-                        gtk::main_quit();
-                        Continue(false)
-                    },
-                    Ok(call) => {
-                        webview.respond(&callback_sym, call.id, call.message);
-                        Continue(true)
+                        },
                     }
                 }
+
+                Continue(true)
             }));
 
             scripts.register_message::<message::Callback<message::Session>, _>(
                 "open_session",
-                clone!(ret, send_auth in move |message| {
+                clone!(ret, send_auth, opening in move |message| {
                     // TODO: Answer even if not Ok(_)
                     if let Ok(message) = message {
-                        if let Some(entry) = entries
-                                .lock()
-                                .expect("Entries mutex to be un-poisoned")
-                                .remove(&message.data.key) {
-                            ret.set(Some(send_auth.send(auth::request(message.id, auth::Request::OpenSession))
-                                .map(|_| entry)
-                                .map_err(|e| format!("PAM channel closed, but login was attempted: {}", e))));
+                        catch!(ret, send_auth.send(auth::request(message.id, auth::Request::OpenSession { key: message.data.key }))
+                            .map_err(|e| format!("PAM channel closed, but login was attempted: {}", e)), {
+                            return;
+                        });
 
-                            gtk::main_quit();
-                        } else {
-                            eprintln!("Could not find entry: {}", message.data.key);
-                        }
+                        opening.set(Some(message.id));
                     }
                 }),
             );
@@ -340,25 +598,144 @@ fn webkit(
                 }),
             );
 
+            scripts.register_message::<message::Callback<message::Autologin>, _>(
+                "autologin",
+                clone!(ret, send_auth in move |message| {
+                    if let Ok(message) = message {
+                        catch!(ret, send_auth.send(auth::request(message.id, auth::Request::Autologin))
+                            .map_err(|e| format!("PAM channel closed, but automatic login was attempted: {}", e)), {
+                            gtk::main_quit();
+                        });
+                    };
+                }),
+            );
+
+            scripts.register_message::<message::Callback<message::CancelAutologin>, _>(
+                "cancel_autologin",
+                clone!(ret, send_auth in move |message| {
+                    if let Ok(message) = message {
+                        catch!(ret, send_auth.send(auth::request(message.id, auth::Request::CancelAutologin))
+                            .map_err(|e| format!("PAM channel closed, but automatic login was canceled: {}", e)), {
+                            gtk::main_quit();
+                        });
+                    };
+                }),
+            );
+
+            for (name, action) in &[
+                ("shutdown", power::Action::Shutdown),
+                ("restart", power::Action::Restart),
+                ("suspend", power::Action::Suspend),
+                ("hibernate", power::Action::Hibernate),
+            ] {
+                let action = *action;
+
+                scripts.register_message::<message::Callback<message::Power>, _>(
+                    name,
+                    clone!(ret, send_auth in move |message| {
+                        if let Ok(message) = message {
+                            catch!(ret, send_auth.send(auth::request(message.id, auth::Request::Power(action)))
+                                .map_err(|e| format!("PAM channel closed, but power action was attempted: {}", e)), {
+                                gtk::main_quit();
+                            });
+                        };
+                    }),
+                );
+            }
+
+            scripts.register_message::<message::Callback<message::ChangePassword>, _>(
+                "change_password",
+                clone!(ret, send_auth in move |message| {
+                    if let Ok(message) = message {
+                        catch!(ret, send_auth.send(auth::request(message.id, auth::Request::ChangePassword))
+                            .map_err(|e| format!("PAM channel closed, but password change was attempted: {}", e)), {
+                            gtk::main_quit();
+                        });
+                    };
+                }),
+            );
+
+            scripts.register_message::<message::Answer, _>("answer", move |message| {
+                if let Ok(message) = message {
+                    let answer = match message.response {
+                        Some(response) => auth::Answer::Respond(response),
+                        None => auth::Answer::Cancel,
+                    };
+
+                    if let Err(e) = answers.send(answer) {
+                        error!("Could not send answer to PAM conversation: {}", e);
+                    }
+                }
+            });
+
             scripts.register_message::<message::Callback<message::Exit>, _>("exit", move |message| {
-                println!("Exit handler");
+                debug!("Exit handler");
                 if let Ok(_) = message {
-                    println!("Ok message");
+                    debug!("Ok message");
                     gtk::main_quit();
                 };
             });
 
+            let theme_name = Rc::new(theme_name);
+
+            // WebKit only hands console messages to web extensions, so the theme's console and
+            // uncaught exceptions are forwarded by the injected script instead
+            let limit = logging::RateLimit::new(THEME_LOG_LIMIT, std::time::Duration::from_secs(10));
+
+            scripts.register_message::<message::Log, _>("log", clone!(theme_name in move |message| {
+                let level = message.as_ref().map(|message| message.level.level()).unwrap_or(Level::Warn);
+
+                if !log_enabled!(target: THEME_LOG_TARGET, level) {
+                    return;
+                }
+
+                match limit.admit() {
+                    Some(0) => {}
+                    Some(dropped) => warn!(target: THEME_LOG_TARGET, "{}: Dropped {} messages logged too quickly", theme_name, dropped),
+                    None => return,
+                }
+
+                match message {
+                    Ok(message::Log { message, source: Some(source), line, column, .. }) => log!(
+                        target: THEME_LOG_TARGET,
+                        level,
+                        "{}: {} ({}:{}:{})",
+                        theme_name,
+                        logging::escape(&message),
+                        logging::escape(&source),
+                        line.unwrap_or(0),
+                        column.unwrap_or(0)
+                    ),
+                    Ok(message) => {
+                        log!(target: THEME_LOG_TARGET, level, "{}: {}", theme_name, logging::escape(&message.message))
+                    }
+                    Err(e) => warn!(target: THEME_LOG_TARGET, "{}: Malformed log message: {:?}", theme_name, e),
+                }
+            }));
+
+            webview.connect_load_failed(clone!(theme_name in move |_, _, uri, e| {
+                error!(target: THEME_LOG_TARGET, "{}: Failed to load {}: {}", theme_name, logging::escape(uri), e);
+                false
+            }));
+
+            webview.connect_web_process_crashed(clone!(theme_name in move |_| {
+                error!(target: THEME_LOG_TARGET, "{}: Web process crashed", theme_name);
+                false
+            }));
+
             webview.connect_context_menu(move |_, menu, _, _| {
                 menu.remove_all();
 
                 return true;
             });
 
+            context.serve_directory(THEME_SCHEME, THEME_HOST, theme_path.into());
+
             if secure {
-                webview.only_accept_from("localhost", 8742);
+                webview.only_accept_from(THEME_SCHEME, THEME_HOST);
             }
 
-            webview.load_uri(&format!("http://localhost:8742/{}", "index.html"));
+            webview.load_uri(&format!("{}://{}/{}", THEME_SCHEME, THEME_HOST, "index.html"));
 
             let window = Window::new(WindowType::Toplevel);
 
@@ -398,102 +775,263 @@ fn webkit(
                 Inhibit(false)
             });
 
+            for &signal in &[libc::SIGTERM, libc::SIGINT] {
+                safe::glib::signal_watch(signal, clone!(ret in move || {
+                    ret.set(Some(Err("Greeter was asked to stop".into())));
+                    gtk::main_quit();
+                    Continue(false)
+                }));
+            }
+
             gtk::main();
-            println!("GTK main finished");
+            debug!("GTK main finished");
             webview.destroy();
             window.destroy();
 
-            println!("GTK process finished");
+            info!("GTK process finished");
             ret.replace(None).unwrap_or_else(|| Err("Unexpected program exit".into()))
-        })
+        }).map_err(greeter::GreeterError::from)
     }).map_err(|e| {
         ProgramError::GenericError(format!("Could not fork to create gtk process: {}", e))
     })?;
 
-    println!("Waiting for GTK process to exit...");
+    signals.set_greeter(Some(gtk_proc.pid()));
 
-    let kill_http = || {
-        println!("Killing HTTP server");
-        nix::sys::signal::kill(http_server, nix::sys::signal::Signal::SIGKILL).map_err(|e| {
-            ProgramError::GenericError(format!("Could not kill http server: {}", e))
-        })?;
+    let exited = authenticator.sender();
+    gtk_proc.on_exit(move || {
+        if let Err(e) = exited.send(auth::request(0, auth::Request::GreeterExited)) {
+            error!("Could not report that the GTK process exited: {}", e);
+        }
+    });
 
-        println!("Waiting for HTTP server to close...");
-        nix::sys::wait::waitpid(http_server, None).map_err(|e| {
-            ProgramError::GenericError(format!("Failed to wait for HTTP server: {}", e))
-        })?;
-        println!("HTTP server closed!");
+    debug!("Waiting for GTK process to exit...");
+    let served = authenticator.serve(&entries);
+    signals.set_greeter(None);
 
-        Ok(())
-    };
+    served.map_err(|e| {
+        ProgramError::GenericError(format!(
+            "Could not receive requests from GTK process: {:?}",
+            e
+        ))
+    })?;
 
-    let wm = loop {
-        if let Ok(true) = safe::libc::is_alive(http_server) {
-        } else {
-            return Err(ProgramError::GenericError(format!(
-                "HTTP server died unexpectedly"
-            )));
+    let result = match gtk_proc.wait() {
+        Err(e @ safe::libc::WaitError::Stopped(_)) | Err(e @ safe::libc::WaitError::Continued) => {
+            warn!("GTK process {}, killing it", e);
+            gtk_proc.kill().ok();
+            gtk_proc.wait()
         }
+        result => result,
+    };
 
-        if let Err(err) = authenticator.drain() {
-            eprintln!("PAM error: {:?}", err);
-        }
+    result
+        .map_err(|e| match e {
+            safe::libc::WaitError::Nix(e) => {
+                ProgramError::GenericError(format!("Could not wait for GTK process: {}", e))
+            }
+            e => ProgramError::GreeterCrashed(e),
+        })?
+        .map_err(|e| {
+            ProgramError::GenericError(format!(
+                "IPC error between GTK process and main process: {}",
+                e
+            ))
+        })?
+        .map_err(ProgramError::from)?;
+
+    // Only what the main process itself authenticated and opened is started
+    let wm = authenticator.opened_session().map_err(|failure| {
+        ProgramError::GenericError(format!(
+            "Greeter exited without an opened session: {}",
+            failure.message()
+        ))
+    })?;
 
-        if let Some(wm_entry) = gtk_proc
-            .poll_finished()
-            .map_err(|e| {
-                ProgramError::GenericError(format!("Could not wait for GTK process: {:?}", e))
-            })
-            .map_err(|e| kill_http().map(|_| e).unwrap_or_else(|e| e))?
-        {
-            break wm_entry
-                .map_err(|e| {
-                    ProgramError::GenericError(format!(
-                        "IPC error between GTK process and main process: {}",
-                        e
-                    ))
-                })
-                .map_err(|e| kill_http().map(|_| e).unwrap_or_else(|e| e))?
-                .map_err(|msg| ProgramError::GenericError(msg))
-                .map_err(|e| kill_http().map(|_| e).unwrap_or_else(|e| e))?;
-        }
-    };
+    Ok((wm, authenticator))
+}
 
-    let env = authenticator
-        .pam()
-        .environment()
+fn spawn_session(
+    authenticator: &mut auth::Auth<'static>,
+    session: &session::SessionEntry,
+    config: &parameterization::Session,
+) -> Result<(std::process::Child, Option<std::process::Child>), ProgramError> {
+    let command = session
+        .entry
+        .exec
+        .as_ref()
         .ok_or(ProgramError::GenericError(
-            "Could not get PAM environment".into(),
+            "Session has no Exec line".into(),
         ))?;
+    let tty = match session.typ {
+        session::SessionType::Wayland => Some(
+            CString::new(format!("/dev/tty{}", authenticator.vtnr()))
+                .expect("tty path should not contain any nul bytes"),
+        ),
+        session::SessionType::X11 => None,
+    };
 
     let user = users::get_user_by_name(authenticator.pam().handler().username()).ok_or(
         ProgramError::GenericError("Could not find user in user database".into()),
     )?;
+    let username = CString::new(authenticator.pam().handler().username()).map_err(|_| {
+        ProgramError::GenericError("User name should not contain any nul bytes".into())
+    })?;
+    // Resolved here, as the group database must not be read between fork and exec
+    let groups = safe::libc::user_groups(&username, user.primary_group_id()).map_err(|e| {
+        ProgramError::GenericError(format!("Could not look up groups of user: {}", e))
+    })?;
 
-    println!("Spawning wm");
-    let spawned = unsafe {
-        std::process::Command::new(wm.exec.as_ref().unwrap_or(&wm.name))
-            .uid(user.uid())
-            .gid(user.primary_group_id())
-            .env_clear()
-            .envs(env.iter().filter_map(|name_value| {
-                let mut parts = name_value.to_str().ok()?.splitn(2, '=');
+    let mut env: Vec<(String, String)> = authenticator
+        .pam()
+        .environment()
+        .ok_or(ProgramError::GenericError(
+            "Could not get PAM environment".into(),
+        ))?
+        .iter()
+        .filter_map(|name_value| {
+            let mut parts = name_value.to_str().ok()?.splitn(2, '=');
+
+            Some((parts.next()?.to_owned(), parts.next()?.to_owned()))
+        })
+        .collect();
+
+    let has = |env: &[(String, String)], name: &str| env.iter().any(|(n, _)| n == name);
+
+    if !has(&env, "PATH") {
+        let path = config
+            .env_path
+            .clone()
+            .unwrap_or_else(|| session::login_path(user.uid() == 0));
+        env.push(("PATH".into(), path));
+    }
+
+    if !has(&env, "XDG_RUNTIME_DIR") {
+        let runtime_dir = format!("/run/user/{}", user.uid());
+        let created = session::ensure_runtime_dir(
+            std::path::Path::new(&runtime_dir),
+            user.uid(),
+            user.primary_group_id(),
+        );
+
+        match created {
+            Ok(()) => env.push(("XDG_RUNTIME_DIR".into(), runtime_dir)),
+            Err(e) => warn!("Could not create runtime directory {}: {}", runtime_dir, e),
+        }
+    }
+
+    let wrapper = match session.typ {
+        session::SessionType::X11 => config.wrapper.as_ref(),
+        session::SessionType::Wayland => config.wayland_wrapper.as_ref(),
+    };
+
+    let (program, args) = match wrapper {
+        Some(wrapper) => {
+            info!("Spawning wm through {}", wrapper);
+            (wrapper.clone(), vec![command.to_shell()])
+        }
+        None => {
+            info!("Spawning wm");
+            (command.program.clone(), command.args.clone())
+        }
+    };
+
+    let mut process = if config.login_shell {
+        let shell = user.shell().to_string_lossy().into_owned();
+        info!("Starting session through login shell {}", shell);
+
+        let command_line = std::iter::once(&program)
+            .chain(args.iter())
+            .map(|arg| freedesktop::shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut process = std::process::Command::new(&shell);
+        process
+            .arg("-l")
+            .arg("-c")
+            .arg(format!("exec {}", command_line));
+        process
+    } else {
+        let mut process = std::process::Command::new(&program);
+        process.args(&args);
+        process
+    };
+
+    let mut log_file = None;
+    let mut logger = None;
+
+    match config.log {
+        parameterization::SessionLog::File => {
+            let xorg = user.home_dir().join(".local/share/xorg");
+            let path = if xorg.is_dir() {
+                xorg.join("xsession-errors")
+            } else {
+                user.home_dir().join(".xsession-errors")
+            };
+            info!("Writing session output to {}", path.display());
+
+            let to_cstring = |path: std::path::PathBuf| {
+                CString::new(path.into_os_string().into_vec()).map_err(|_| {
+                    ProgramError::GenericError("Session log path contains a nul byte".into())
+                })
+            };
 
-                Some((parts.next()?, parts.next()?))
-            }))
+            log_file = Some((to_cstring(path.with_extension("old"))?, to_cstring(path)?));
+        }
+        parameterization::SessionLog::Syslog => {
+            let mut child = std::process::Command::new("logger")
+                .arg("-t")
+                .arg(format!("webdm-session-{}", session.id))
+                .stdin(std::process::Stdio::piped())
+                .spawn()
+                .map_err(|e| {
+                    ProgramError::GenericError(format!("Could not start session logger: {}", e))
+                })?;
+
+            let pipe = child
+                .stdin
+                .take()
+                .expect("Logger should have been spawned with a piped stdin");
+            let stderr = nix::unistd::dup(pipe.as_raw_fd()).map_err(|e| {
+                ProgramError::GenericError(format!("Could not duplicate logger pipe: {}", e))
+            })?;
+
+            process
+                .stdout(std::process::Stdio::from(pipe))
+                .stderr(unsafe { std::process::Stdio::from_raw_fd(stderr) });
+            logger = Some(child);
+        }
+        parameterization::SessionLog::Inherit => {}
+    }
+
+    unsafe {
+        process
+            .env_clear()
+            .envs(env)
             .pre_exec(move || {
-                chdir(user.home_dir()).map_err(|e| {
+                let nix_to_io = |e: nix::Error| {
                     e.as_errno()
                         .map(From::from)
                         .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, e))
-                })
-            })
-            .spawn()
-            .map_err(|e| ProgramError::GenericError(format!("Could not start xsession: {}", e)))?
-    };
+                };
 
-    println!("VM running");
-    kill_http()?;
+                if let Some(ref tty) = tty {
+                    safe::libc::take_controlling_tty(tty).map_err(nix_to_io)?;
+                } else {
+                    nix::unistd::setsid().map_err(nix_to_io)?;
+                }
+
+                safe::libc::become_user(&groups, user.uid(), user.primary_group_id())
+                    .map_err(nix_to_io)?;
+
+                if let Some((ref old, ref path)) = log_file {
+                    safe::libc::redirect_output(path, old).map_err(nix_to_io)?;
+                }
 
-    Ok((spawned, authenticator.into_pam()))
+                chdir(user.home_dir()).map_err(nix_to_io)
+            })
+            .spawn()
+            .map(|child| (child, logger))
+            .map_err(|e| ProgramError::GenericError(format!("Could not start session: {}", e)))
+    }
 }
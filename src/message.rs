@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+
+use crate::session::SessionType;
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct Callback<T> {
     pub(crate) id: u64,
@@ -8,15 +11,66 @@ pub(crate) struct Callback<T> {
 #[derive(Deserialize, Debug)]
 pub(crate) struct Login {
     pub(crate) username: String,
-    pub(crate) password: String,
+    pub(crate) password: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Answer {
+    pub(crate) response: Option<String>,
 }
 
+#[derive(Deserialize, Debug)]
+pub(crate) struct ChangePassword {}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Autologin {}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct CancelAutologin {}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Power {}
+
 #[derive(Deserialize, Debug)]
 pub(crate) enum Exit {}
 
+/// Console method a theme message was logged with, uncaught exceptions are logged as errors
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConsoleLevel {
+    Debug,
+    Log,
+    Info,
+    Warn,
+    Error,
+}
+
+impl ConsoleLevel {
+    pub(crate) fn level(self) -> log::Level {
+        match self {
+            ConsoleLevel::Debug => log::Level::Debug,
+            ConsoleLevel::Log | ConsoleLevel::Info => log::Level::Info,
+            ConsoleLevel::Warn => log::Level::Warn,
+            ConsoleLevel::Error => log::Level::Error,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Log {
+    pub(crate) level: ConsoleLevel,
+    pub(crate) message: String,
+    pub(crate) source: Option<String>,
+    pub(crate) line: Option<u32>,
+    pub(crate) column: Option<u32>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub(crate) struct Session {
     pub(crate) name: String,
     pub(crate) key: u64,
+    pub(crate) id: String,
+    #[serde(rename = "type")]
+    pub(crate) typ: SessionType,
     pub(crate) comment: String,
 }
@@ -2,14 +2,22 @@ use clap::{Arg, ArgMatches};
 use serde::Deserialize;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::Read;
 
+#[derive(Clone)]
 pub(crate) struct Config {
     pub(crate) xorg: XOrgConfig,
     pub(crate) theme: Theme,
+    pub(crate) greeter: Greeter,
     pub(crate) create_x_server: bool,
     pub(crate) session: Session,
     pub(crate) users: Users,
+    pub(crate) autologin: Option<Autologin>,
+    pub(crate) restart: Restart,
+    pub(crate) power: Power,
+    pub(crate) state: State,
+    pub(crate) log: Log,
 }
 #[derive(Deserialize)]
 struct ConfigFile {
@@ -19,6 +27,17 @@ struct ConfigFile {
     session: Session,
     #[serde(default)]
     users: Users,
+    autologin: Option<Autologin>,
+    #[serde(default)]
+    restart: Restart,
+    #[serde(default)]
+    power: Power,
+    #[serde(default)]
+    state: State,
+    #[serde(default)]
+    greeter: Greeter,
+    #[serde(default)]
+    log: Log,
     theme: Theme,
 }
 
@@ -34,10 +53,66 @@ fn default_vt() -> String {
     "vt7".into()
 }
 
+fn default_runtime_dir() -> String {
+    "/run/webdm".into()
+}
+
+fn default_state_path() -> String {
+    "/var/lib/webdm/state.toml".into()
+}
+
+fn default_log_level() -> LogLevel {
+    LogLevel::Info
+}
+
+fn default_log_output() -> LogOutput {
+    LogOutput::Stderr
+}
+
+fn default_log_path() -> String {
+    "/var/log/webdm/webdm.log".into()
+}
+
+fn default_log_max_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_log_keep() -> u32 {
+    5
+}
+
+fn true_bool() -> bool {
+    true
+}
+
 fn default_home() -> String {
     "/home/".into()
 }
 
+fn default_restart_policy() -> RestartPolicy {
+    RestartPolicy::Never
+}
+
+fn default_power_backend() -> PowerBackend {
+    PowerBackend::Logind
+}
+
+fn default_max_failures() -> u32 {
+    5
+}
+
+fn default_backoff() -> u64 {
+    2
+}
+
+fn default_max_backoff() -> u64 {
+    60
+}
+
+fn default_session_log() -> SessionLog {
+    SessionLog::File
+}
+
 fn default_session() -> Option<String> {
     None
 }
@@ -46,16 +121,41 @@ fn default_sessions_dir() -> String {
     "/usr/share/xsessions".into()
 }
 
-#[derive(Deserialize)]
+fn default_wayland_sessions_dir() -> String {
+    "/usr/share/wayland-sessions".into()
+}
+
+#[derive(Deserialize, Clone)]
 pub(crate) struct Theme {
     pub(crate) path: String,
     #[serde(default = "false_bool")]
     pub(crate) debug: bool,
     #[serde(default = "false_bool")]
     pub(crate) allow_external_resources: bool,
+    /// Locale used for session names and comments, defaults to the environment's locale
+    pub(crate) language: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Default)]
+pub(crate) struct Greeter {
+    /// Unprivileged user the greeter runs as, the greeter stays root if unset
+    pub(crate) user: Option<String>,
+    #[serde(default)]
+    pub(crate) sandbox: Sandbox,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub(crate) struct Sandbox {
+    /// Run the greeter in its own mount and network namespaces with PR_SET_NO_NEW_PRIVS, needs
+    /// `user` to be set
+    #[serde(default = "false_bool")]
+    pub(crate) enabled: bool,
+    /// Also deny syscalls the greeter never needs through seccomp
+    #[serde(default = "false_bool")]
+    pub(crate) seccomp: bool,
+}
+
+#[derive(Deserialize, Clone)]
 pub(crate) struct Users {
     #[serde(default = "false_bool")]
     pub(crate) hide: bool,
@@ -63,27 +163,208 @@ pub(crate) struct Users {
     pub(crate) home_prefix: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub(crate) struct Session {
     #[serde(default = "default_sessions_dir")]
     pub(crate) path: String,
+    #[serde(default = "default_wayland_sessions_dir")]
+    pub(crate) wayland_path: String,
     #[serde(default = "default_session")]
     pub(crate) default: Option<String>,
+    /// Script that X11 sessions are started through, e.g. /etc/X11/Xsession. Like with other
+    /// display managers, it receives the session's command as its only argument, with field codes
+    /// expanded and every word quoted for the shell.
+    pub(crate) wrapper: Option<String>,
+    /// Script that Wayland sessions are started through, called like `wrapper`
+    pub(crate) wayland_wrapper: Option<String>,
+    #[serde(default = "default_session_log")]
+    pub(crate) log: SessionLog,
+    /// PATH for sessions when PAM does not set one, defaults to the value in /etc/login.defs
+    pub(crate) env_path: Option<String>,
+    /// Start sessions through the user's shell as a login shell
+    #[serde(default = "false_bool")]
+    pub(crate) login_shell: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SessionLog {
+    /// Write to ~/.local/share/xorg/xsession-errors, or ~/.xsession-errors if that directory
+    /// does not exist, keeping the previous log as `.old`
+    File,
+    /// Send every line to syslog through logger(1)
+    Syslog,
+    /// Share WebDM's own stdout and stderr
+    Inherit,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct Autologin {
+    pub(crate) user: String,
+    pub(crate) session: Option<String>,
+    pub(crate) delay: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RestartPolicy {
+    /// Exit after the first session ends
+    Never,
+    /// Return to the greeter after a session ends, but exit if the greeter fails
+    Session,
+    /// Always return to the greeter
+    Always,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct Restart {
+    #[serde(default = "default_restart_policy")]
+    pub(crate) policy: RestartPolicy,
+    #[serde(default = "default_max_failures")]
+    pub(crate) max_failures: u32,
+    #[serde(default = "default_backoff")]
+    pub(crate) backoff: u64,
+    #[serde(default = "default_max_backoff")]
+    pub(crate) max_backoff: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PowerBackend {
+    /// Ask systemd-logind through `busctl`, which must be installed
+    Logind,
+    /// Run the configured shell commands, actions without one are unavailable
+    Commands,
+    /// Offer no power actions
+    None,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct Power {
+    #[serde(default = "default_power_backend")]
+    pub(crate) backend: PowerBackend,
+    pub(crate) shutdown: Option<String>,
+    pub(crate) restart: Option<String>,
+    pub(crate) suspend: Option<String>,
+    pub(crate) hibernate: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct State {
+    #[serde(default = "default_state_path")]
+    pub(crate) path: String,
+    #[serde(default = "true_bool")]
+    pub(crate) dmrc: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("Unknown log level '{}'", level)),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogOutput {
+    Stderr,
+    /// Append to `path`, rotating it once it grows beyond `max_size`
+    File,
+    Syslog,
+}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct Log {
+    #[serde(default = "default_log_level")]
+    pub(crate) level: LogLevel,
+    /// Levels for single modules, e.g. `auth = "debug"`. Theme console output is logged as
+    /// `theme`, which defaults to `warn`.
+    #[serde(default)]
+    pub(crate) modules: HashMap<String, LogLevel>,
+    #[serde(default = "default_log_output")]
+    pub(crate) output: LogOutput,
+    #[serde(default = "default_log_path")]
+    pub(crate) path: String,
+    #[serde(default = "default_log_max_size")]
+    pub(crate) max_size: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "default_log_keep")]
+    pub(crate) keep: u32,
+}
+
+#[derive(Deserialize, Clone)]
 pub(crate) struct XOrgConfig {
     #[serde(default = "default_display")]
     pub(crate) display: String,
     #[serde(default = "default_vt")]
     pub(crate) vt: String,
+    #[serde(default = "default_runtime_dir")]
+    pub(crate) runtime_dir: String,
 }
 
 impl Default for Session {
     fn default() -> Self {
         Self {
             path: default_sessions_dir(),
+            wayland_path: default_wayland_sessions_dir(),
             default: default_session(),
+            wrapper: None,
+            wayland_wrapper: None,
+            log: default_session_log(),
+            env_path: None,
+            login_shell: false,
+        }
+    }
+}
+
+impl Default for Restart {
+    fn default() -> Self {
+        Self {
+            policy: default_restart_policy(),
+            max_failures: default_max_failures(),
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+
+impl Default for Power {
+    fn default() -> Self {
+        Self {
+            backend: default_power_backend(),
+            shutdown: None,
+            restart: None,
+            suspend: None,
+            hibernate: None,
+        }
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            path: default_state_path(),
+            dmrc: true_bool(),
         }
     }
 }
@@ -97,11 +378,25 @@ impl Default for Users {
     }
 }
 
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            modules: HashMap::new(),
+            output: default_log_output(),
+            path: default_log_path(),
+            max_size: default_log_max_size(),
+            keep: default_log_keep(),
+        }
+    }
+}
+
 impl Default for XOrgConfig {
     fn default() -> Self {
         Self {
             display: default_display(),
             vt: default_vt(),
+            runtime_dir: default_runtime_dir(),
         }
     }
 }
@@ -110,6 +405,7 @@ impl Default for XOrgConfig {
 pub(crate) enum ConfigError {
     Io(std::io::Error),
     InvalidConfig(toml::de::Error),
+    InvalidArgument(String),
 }
 
 impl From<ConfigError> for crate::ProgramError {
@@ -149,21 +445,64 @@ impl Config {
             xorg: XOrgConfig {
                 display: get(&matches, "DISPLAY", config.xorg.display),
                 vt: get(&matches, "VT", config.xorg.vt),
+                runtime_dir: config.xorg.runtime_dir,
             },
             theme: Theme {
                 path: get(&matches, "THEME", config.theme.path),
                 debug: matches.is_present("DEBUG") || config.theme.debug,
                 allow_external_resources: matches.is_present("INSECURE")
                     || config.theme.allow_external_resources,
+                language: matches
+                    .value_of("LANGUAGE")
+                    .map(Into::into)
+                    .or(config.theme.language),
+            },
+            greeter: Greeter {
+                user: matches
+                    .value_of("GREETER_USER")
+                    .map(Into::into)
+                    .or(config.greeter.user),
+                sandbox: Sandbox {
+                    enabled: matches.is_present("SANDBOX") || config.greeter.sandbox.enabled,
+                    ..config.greeter.sandbox
+                },
             },
             session: Session {
                 path: get(&matches, "SESSIONS", config.session.path),
+                wayland_path: get(&matches, "WAYLAND_SESSIONS", config.session.wayland_path),
                 default: config.session.default,
+                wrapper: config.session.wrapper,
+                wayland_wrapper: config.session.wayland_wrapper,
+                log: config.session.log,
+                env_path: config.session.env_path,
+                login_shell: config.session.login_shell,
             },
             users: Users {
                 hide: matches.is_present("HIDEUSERS") || config.users.hide,
                 home_prefix: get(&matches, "HOME", config.users.home_prefix),
             },
+            autologin: if matches.is_present("NO_AUTOLOGIN") {
+                None
+            } else {
+                config.autologin
+            },
+            restart: Restart {
+                policy: if matches.is_present("DAEMON") {
+                    RestartPolicy::Always
+                } else {
+                    config.restart.policy
+                },
+                ..config.restart
+            },
+            power: config.power,
+            state: config.state,
+            log: Log {
+                level: match matches.value_of("LOG_LEVEL") {
+                    Some(level) => level.parse().map_err(ConfigError::InvalidArgument)?,
+                    None => config.log.level,
+                },
+                ..config.log
+            },
         })
     }
 }
@@ -215,11 +554,35 @@ impl<'a, 'b> WebDMApp<'a, 'b> {
                         .long("allow-external-resources")
                         .help("Path of the directory with the theme website"),
                 )
+                .arg(
+                    Arg::with_name("LOG_LEVEL")
+                        .long("log-level")
+                        .takes_value(true)
+                        .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                        .help("Minimum level of log messages"),
+                )
                 .arg(
                     Arg::with_name("DEBUG")
                         .long("debug")
                         .help("Path of the directory with the theme website"),
                 )
+                .arg(
+                    Arg::with_name("LANGUAGE")
+                        .long("language")
+                        .takes_value(true)
+                        .help("Locale used for session names, e.g. de_DE"),
+                )
+                .arg(
+                    Arg::with_name("GREETER_USER")
+                        .long("greeter-user")
+                        .takes_value(true)
+                        .help("Unprivileged user to run the greeter as"),
+                )
+                .arg(
+                    Arg::with_name("SANDBOX")
+                        .long("sandbox")
+                        .help("Run the greeter in a sandbox"),
+                )
                 .arg(
                     Arg::with_name("HOME")
                         .takes_value(true)
@@ -231,12 +594,28 @@ impl<'a, 'b> WebDMApp<'a, 'b> {
                         .long("hide-users")
                         .help("Hide users from webkit theme"),
                 )
+                .arg(
+                    Arg::with_name("DAEMON")
+                        .long("daemon")
+                        .help("Always return to the greeter after a session ends"),
+                )
+                .arg(
+                    Arg::with_name("NO_AUTOLOGIN")
+                        .long("no-autologin")
+                        .help("Ignore the autologin section of the configuration file"),
+                )
                 .arg(
                     Arg::with_name("SESSIONS")
                         .takes_value(true)
                         .short("s")
                         .long("sessions")
                         .help("Directory path where the DesktopEntry xsessions are"),
+                )
+                .arg(
+                    Arg::with_name("WAYLAND_SESSIONS")
+                        .takes_value(true)
+                        .long("wayland-sessions")
+                        .help("Directory path where the DesktopEntry wayland sessions are"),
                 ),
         }
     }
@@ -0,0 +1,181 @@
+#[cfg(test)]
+mod tests;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::parameterization;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Action {
+    Shutdown,
+    Restart,
+    Suspend,
+    Hibernate,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Shutdown => "shutdown",
+            Action::Restart => "restart",
+            Action::Suspend => "suspend",
+            Action::Hibernate => "hibernate",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PowerError {
+    Unsupported,
+    Io(std::io::Error),
+    Failed(String),
+}
+
+impl std::fmt::Display for PowerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PowerError::Unsupported => write!(f, "not supported"),
+            PowerError::Io(e) => write!(f, "{}", e),
+            PowerError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+pub(crate) trait Backend {
+    fn can(&self, action: Action) -> bool;
+    fn perform(&self, action: Action) -> Result<(), PowerError>;
+}
+
+pub(crate) fn backend(config: &parameterization::Power) -> Box<dyn Backend> {
+    match config.backend {
+        parameterization::PowerBackend::Logind => Box::new(Logind),
+        parameterization::PowerBackend::Commands => Box::new(Commands {
+            shutdown: config.shutdown.clone(),
+            restart: config.restart.clone(),
+            suspend: config.suspend.clone(),
+            hibernate: config.hibernate.clone(),
+        }),
+        parameterization::PowerBackend::None => Box::new(Disabled),
+    }
+}
+
+fn run(command: &mut std::process::Command) -> Result<String, PowerError> {
+    let output = command.output().map_err(PowerError::Io)?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(PowerError::Failed(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
+
+/// Talks to systemd-logind over the system bus through `busctl`.
+pub(crate) struct Logind;
+
+impl Logind {
+    fn method(action: Action) -> &'static str {
+        match action {
+            Action::Shutdown => "PowerOff",
+            Action::Restart => "Reboot",
+            Action::Suspend => "Suspend",
+            Action::Hibernate => "Hibernate",
+        }
+    }
+
+    fn call(method: &str, args: &[&str]) -> Result<String, PowerError> {
+        run(std::process::Command::new("busctl")
+            .arg("call")
+            .arg("org.freedesktop.login1")
+            .arg("/org/freedesktop/login1")
+            .arg("org.freedesktop.login1.Manager")
+            .arg(method)
+            .args(args))
+    }
+
+    /// Extracts the value of a string reply, which `busctl` prints as `s "value"`.
+    fn string_reply(reply: &str) -> Option<&str> {
+        let mut parts = reply.trim().splitn(2, char::is_whitespace);
+
+        match (parts.next(), parts.next().map(str::trim)) {
+            (Some("s"), Some(value))
+                if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') =>
+            {
+                Some(&value[1..value.len() - 1])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Backend for Logind {
+    fn can(&self, action: Action) -> bool {
+        match Logind::call(&format!("Can{}", Logind::method(action)), &[]) {
+            Ok(reply) => match Logind::string_reply(&reply) {
+                Some("yes") | Some("challenge") => true,
+                Some(_) => false,
+                None => {
+                    warn!(
+                        "Unexpected reply from logind for {}: {}",
+                        action.name(),
+                        reply.trim()
+                    );
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Could not query logind for {}: {}", action.name(), e);
+                false
+            }
+        }
+    }
+
+    fn perform(&self, action: Action) -> Result<(), PowerError> {
+        Logind::call(Logind::method(action), &["b", "true"]).map(|_| ())
+    }
+}
+
+/// Runs a configured shell command for every action.
+pub(crate) struct Commands {
+    shutdown: Option<String>,
+    restart: Option<String>,
+    suspend: Option<String>,
+    hibernate: Option<String>,
+}
+
+impl Commands {
+    fn command(&self, action: Action) -> Option<&String> {
+        match action {
+            Action::Shutdown => self.shutdown.as_ref(),
+            Action::Restart => self.restart.as_ref(),
+            Action::Suspend => self.suspend.as_ref(),
+            Action::Hibernate => self.hibernate.as_ref(),
+        }
+    }
+}
+
+impl Backend for Commands {
+    fn can(&self, action: Action) -> bool {
+        self.command(action).is_some()
+    }
+
+    fn perform(&self, action: Action) -> Result<(), PowerError> {
+        let command = self.command(action).ok_or(PowerError::Unsupported)?;
+
+        run(std::process::Command::new("/bin/sh").arg("-c").arg(command)).map(|_| ())
+    }
+}
+
+pub(crate) struct Disabled;
+
+impl Backend for Disabled {
+    fn can(&self, _action: Action) -> bool {
+        false
+    }
+
+    fn perform(&self, _action: Action) -> Result<(), PowerError> {
+        Err(PowerError::Unsupported)
+    }
+}
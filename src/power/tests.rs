@@ -0,0 +1,36 @@
+use super::*;
+
+#[test]
+fn runs_configured_commands() {
+    let commands = Commands {
+        shutdown: Some("true".to_owned()),
+        restart: Some("false".to_owned()),
+        suspend: None,
+        hibernate: None,
+    };
+
+    assert!(commands.can(Action::Shutdown));
+    assert!(commands.can(Action::Restart));
+    assert!(!commands.can(Action::Suspend));
+    assert!(!commands.can(Action::Hibernate));
+
+    assert!(commands.perform(Action::Shutdown).is_ok());
+    match commands.perform(Action::Restart) {
+        Err(PowerError::Failed(_)) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    match commands.perform(Action::Suspend) {
+        Err(PowerError::Unsupported) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn parses_logind_replies() {
+    assert_eq!(Logind::string_reply("s \"yes\"\n"), Some("yes"));
+    assert_eq!(Logind::string_reply("s  \"challenge\""), Some("challenge"));
+    assert_eq!(Logind::string_reply("s \"na\""), Some("na"));
+    assert_eq!(Logind::string_reply("s \""), None);
+    assert_eq!(Logind::string_reply("b true"), None);
+    assert_eq!(Logind::string_reply(""), None);
+}
@@ -1,2 +1,3 @@
+pub(crate) mod glib;
 pub(crate) mod libc;
 pub(crate) mod x11;
@@ -0,0 +1,64 @@
+use glib::Continue;
+
+use std::os::unix::io::RawFd;
+
+unsafe extern "C" fn fd_watch_trampoline(
+    _fd: libc::c_int,
+    _condition: glib_sys::GIOCondition,
+    f: glib_sys::gpointer,
+) -> glib_sys::gboolean {
+    let f: &mut Box<dyn FnMut() -> Continue + 'static> = &mut *(f as *mut _);
+
+    if f().0 {
+        glib_sys::GTRUE
+    } else {
+        glib_sys::GFALSE
+    }
+}
+
+unsafe extern "C" fn destroy_closure(f: glib_sys::gpointer) {
+    Box::from_raw(f as *mut Box<dyn FnMut() -> Continue + 'static>);
+}
+
+/// Calls `f` on the default main context whenever `fd` becomes readable or is closed, until `f`
+/// returns `Continue(false)`.
+pub(crate) fn fd_watch<F: FnMut() -> Continue + 'static>(fd: RawFd, f: F) -> u32 {
+    let f: Box<Box<dyn FnMut() -> Continue + 'static>> = Box::new(Box::new(f));
+
+    unsafe {
+        glib_sys::g_unix_fd_add_full(
+            glib_sys::G_PRIORITY_DEFAULT,
+            fd,
+            glib_sys::G_IO_IN | glib_sys::G_IO_HUP | glib_sys::G_IO_ERR,
+            Some(fd_watch_trampoline),
+            Box::into_raw(f) as glib_sys::gpointer,
+            Some(destroy_closure),
+        )
+    }
+}
+
+unsafe extern "C" fn signal_watch_trampoline(f: glib_sys::gpointer) -> glib_sys::gboolean {
+    let f: &mut Box<dyn FnMut() -> Continue + 'static> = &mut *(f as *mut _);
+
+    if f().0 {
+        glib_sys::GTRUE
+    } else {
+        glib_sys::GFALSE
+    }
+}
+
+/// Calls `f` on the default main context whenever `signal` is received, until `f` returns
+/// `Continue(false)`.
+pub(crate) fn signal_watch<F: FnMut() -> Continue + 'static>(signal: libc::c_int, f: F) -> u32 {
+    let f: Box<Box<dyn FnMut() -> Continue + 'static>> = Box::new(Box::new(f));
+
+    unsafe {
+        glib_sys::g_unix_signal_add_full(
+            glib_sys::G_PRIORITY_DEFAULT,
+            signal,
+            Some(signal_watch_trampoline),
+            Box::into_raw(f) as glib_sys::gpointer,
+            Some(destroy_closure),
+        )
+    }
+}
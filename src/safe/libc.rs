@@ -1,4 +1,5 @@
 use ipc_channel::ipc;
+use log::error;
 
 use serde::{de::DeserializeOwned, Serialize};
 use std::ffi::CStr;
@@ -34,14 +35,241 @@ pub(crate) fn setenv<'a>(name: &'a CStr, value: &CStr) -> Result<(), SetEnvError
     }
 }
 
-pub(crate) fn run_in_process<F: FnOnce() -> i32>(f: F) -> nix::Result<nix::unistd::Pid> {
-    Ok(match nix::unistd::fork()? {
-        nix::unistd::ForkResult::Child => {
-            let exit_code = f();
-            std::process::exit(exit_code);
+/// Makes the calling process a session leader with `tty` as its controlling terminal and
+/// standard input. Only meant to be called between `fork` and `exec`.
+pub(crate) fn take_controlling_tty(tty: &CStr) -> nix::Result<()> {
+    use nix::fcntl::{open, OFlag};
+    use nix::sys::stat::Mode;
+
+    let fd = open(tty, OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty())?;
+
+    nix::unistd::setsid()?;
+
+    if unsafe { libc::ioctl(fd, libc::TIOCSCTTY, 0) } < 0 {
+        return Err(nix::Error::last());
+    }
+
+    nix::unistd::dup2(fd, libc::STDIN_FILENO)?;
+    nix::unistd::close(fd)
+}
+
+/// Every group `name` is a member of, including `gid`. Reads the group database, so it must not
+/// be called between `fork` and `exec`.
+pub(crate) fn user_groups(name: &CStr, gid: libc::gid_t) -> nix::Result<Vec<libc::gid_t>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let ret =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
         }
-        nix::unistd::ForkResult::Parent { child } => child,
-    })
+
+        if count as usize <= groups.len() {
+            return Err(nix::Error::Sys(nix::errno::Errno::EINVAL));
+        }
+
+        groups.resize(count as usize, 0);
+    }
+}
+
+/// Switches to `uid` and `gid` with `groups` as the supplementary groups. Safe to call between
+/// `fork` and `exec`.
+pub(crate) fn become_user(
+    groups: &[libc::gid_t],
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+) -> nix::Result<()> {
+    if unsafe { libc::setgroups(groups.len(), groups.as_ptr()) } < 0 {
+        return Err(nix::Error::last());
+    }
+
+    nix::unistd::setgid(nix::unistd::Gid::from_raw(gid))?;
+    nix::unistd::setuid(nix::unistd::Uid::from_raw(uid))
+}
+
+/// Runs `f` with the filesystem uid and gid of another user, so files are opened and created with
+/// that user's permissions. Only the calling thread is affected, and root is restored afterwards.
+pub(crate) fn as_user_fs<T, F: FnOnce() -> T>(uid: libc::uid_t, gid: libc::gid_t, f: F) -> T {
+    let (old_uid, old_gid) = unsafe {
+        let old_gid = libc::setfsgid(gid);
+        let old_uid = libc::setfsuid(uid);
+        (old_uid, old_gid)
+    };
+
+    let ret = f();
+
+    unsafe {
+        libc::setfsuid(old_uid as libc::uid_t);
+        libc::setfsgid(old_gid as libc::gid_t);
+    }
+
+    ret
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "x86")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0003);
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm"
+)))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// x32 syscalls share the x86_64 audit architecture and are only told apart by this bit.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: Option<u32> = Some(0x4000_0000);
+#[cfg(not(target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: Option<u32> = None;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL: u32 = 0;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+fn bpf(code: u16, jt: u8, jf: u8, k: u32) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+/// Sets `PR_SET_NO_NEW_PRIVS`, so neither the calling process nor its children can gain
+/// privileges through exec.
+pub(crate) fn set_no_new_privs() -> nix::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(nix::Error::last());
+    }
+
+    Ok(())
+}
+
+/// Installs a seccomp filter that makes every syscall in `denied` and every x32 syscall fail with
+/// `EPERM`. Processes running under another architecture's syscall convention are killed.
+/// Requires [`set_no_new_privs`] for unprivileged processes.
+pub(crate) fn deny_syscalls(denied: &[libc::c_long]) -> nix::Result<()> {
+    let arch = AUDIT_ARCH.ok_or(nix::Error::Sys(nix::errno::Errno::ENOSYS))?;
+
+    let mut filter = vec![
+        bpf(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_ARCH),
+        bpf(BPF_JEQ_K, 1, 0, arch),
+        bpf(BPF_RET_K, 0, 0, SECCOMP_RET_KILL),
+        bpf(BPF_LD_W_ABS, 0, 0, SECCOMP_DATA_NR),
+    ];
+
+    // Otherwise every denied syscall could still be made through its x32 number
+    if let Some(x32) = X32_SYSCALL_BIT {
+        filter.push(bpf(BPF_JGE_K, 0, 1, x32));
+        filter.push(bpf(BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+
+    for &syscall in denied {
+        filter.push(bpf(BPF_JEQ_K, 0, 1, syscall as u32));
+        filter.push(bpf(BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+    }
+
+    filter.push(bpf(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+
+    let program = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+
+    let ret = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            &program as *const SockFprog,
+        )
+    };
+
+    if ret < 0 {
+        return Err(nix::Error::last());
+    }
+
+    Ok(())
+}
+
+/// Points stdout and stderr at a freshly truncated `path`, moving any previous file to `old`.
+/// Only meant to be called between `fork` and `exec`, after dropping privileges.
+pub(crate) fn redirect_output(path: &CStr, old: &CStr) -> nix::Result<()> {
+    use nix::fcntl::{open, OFlag};
+    use nix::sys::stat::Mode;
+
+    if unsafe { libc::rename(path.as_ptr(), old.as_ptr()) } < 0 {
+        let err = nix::Error::last();
+
+        if err != nix::Error::Sys(nix::errno::Errno::ENOENT) {
+            return Err(err);
+        }
+    }
+
+    let fd = open(
+        path,
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_NOFOLLOW,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+    )?;
+
+    nix::unistd::dup2(fd, libc::STDOUT_FILENO)?;
+    nix::unistd::dup2(fd, libc::STDERR_FILENO)?;
+    nix::unistd::close(fd)
+}
+
+/// Blocks until `pid` has exited without reaping it.
+pub(crate) fn wait_exited(pid: nix::unistd::Pid) -> nix::Result<()> {
+    wait_without_reaping(pid, libc::WEXITED)
+}
+
+/// Blocks until `pid` has exited or was stopped without reaping it.
+pub(crate) fn wait_exited_or_stopped(pid: nix::unistd::Pid) -> nix::Result<()> {
+    wait_without_reaping(pid, libc::WEXITED | libc::WSTOPPED)
+}
+
+fn wait_without_reaping(pid: nix::unistd::Pid, options: libc::c_int) -> nix::Result<()> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+
+    loop {
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid.as_raw() as libc::id_t,
+                &mut info,
+                options | libc::WNOWAIT,
+            )
+        };
+
+        match nix::errno::Errno::result(ret) {
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            result => return result.map(|_| ()),
+        }
+    }
 }
 
 pub(crate) struct ProcessWaitHandle<T: Serialize + DeserializeOwned> {
@@ -52,21 +280,37 @@ pub(crate) struct ProcessWaitHandle<T: Serialize + DeserializeOwned> {
 #[derive(Debug)]
 pub(crate) enum WaitError {
     Nix(nix::Error),
-    BadIpcError,
+    /// The process exited with a non-zero status, for example because it could not send its result
+    BadIpcError(i32),
+    Signaled {
+        signal: nix::sys::signal::Signal,
+        core_dumped: bool,
+    },
+    Stopped(nix::sys::signal::Signal),
+    Continued,
+    Unexpected(nix::sys::wait::WaitStatus),
 }
 
-impl From<nix::Error> for WaitError {
-    fn from(err: nix::Error) -> Self {
-        WaitError::Nix(err)
+impl std::fmt::Display for WaitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WaitError::Nix(e) => write!(f, "{}", e),
+            WaitError::BadIpcError(status) => write!(f, "exited with status {}", status),
+            WaitError::Signaled {
+                signal,
+                core_dumped: true,
+            } => write!(f, "killed by {:?} (core dumped)", signal),
+            WaitError::Signaled { signal, .. } => write!(f, "killed by {:?}", signal),
+            WaitError::Stopped(signal) => write!(f, "stopped by {:?}", signal),
+            WaitError::Continued => write!(f, "continued"),
+            WaitError::Unexpected(status) => write!(f, "unexpected wait status {:?}", status),
+        }
     }
 }
 
-pub(crate) fn is_alive(pid: nix::unistd::Pid) -> nix::Result<bool> {
-    use nix::sys::wait::{WaitPidFlag, WaitStatus};
-
-    match nix::sys::wait::waitpid(pid, Some(WaitPidFlag::WNOHANG))? {
-        WaitStatus::StillAlive => Ok(true),
-        _ => Ok(false),
+impl From<nix::Error> for WaitError {
+    fn from(err: nix::Error) -> Self {
+        WaitError::Nix(err)
     }
 }
 
@@ -74,33 +318,47 @@ impl<T> ProcessWaitHandle<T>
 where
     T: Serialize + DeserializeOwned,
 {
-    pub(crate) fn poll_finished(&self) -> Result<Option<Result<T, ipc_channel::Error>>, WaitError> {
-        use nix::sys::wait::{WaitPidFlag, WaitStatus};
-        // TODO: Fix process exiting uncleanly
-
-        match nix::sys::wait::waitpid(self.pid, Some(WaitPidFlag::WNOHANG))? {
-            WaitStatus::Exited(_pid, 0) => Ok(Some(self.recv.recv())),
-            WaitStatus::Exited(_pid, _status) => Err(WaitError::BadIpcError),
-            WaitStatus::StillAlive => Ok(None),
-            s => {
-                eprintln!("Unexpected wait result: {:?}", s);
-                panic!()
+    pub(crate) fn pid(&self) -> nix::unistd::Pid {
+        self.pid
+    }
+
+    /// Calls `f` from another thread once the process has exited or was stopped. The process is
+    /// not reaped, so its result can still be collected afterwards.
+    pub(crate) fn on_exit<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let pid = self.pid;
+
+        std::thread::spawn(move || {
+            if let Err(e) = wait_exited_or_stopped(pid) {
+                error!("Could not wait for process {}: {}", pid, e);
             }
-        }
+
+            f();
+        });
     }
 
+    /// Reaps the process, or reports that it is stopped or was continued instead.
     pub(crate) fn wait(&self) -> Result<Result<T, ipc_channel::Error>, WaitError> {
-        use nix::sys::wait::WaitStatus;
+        use nix::sys::wait::{WaitPidFlag, WaitStatus};
+
+        let flags = WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
 
-        match nix::sys::wait::waitpid(self.pid, None)? {
+        match nix::sys::wait::waitpid(self.pid, Some(flags))? {
             WaitStatus::Exited(_pid, 0) => Ok(self.recv.recv()),
-            WaitStatus::Exited(_pid, _status) => Err(WaitError::BadIpcError),
-            s => {
-                eprintln!("Unexpected wait result: {:?}", s);
-                panic!()
-            }
+            WaitStatus::Exited(_pid, status) => Err(WaitError::BadIpcError(status)),
+            WaitStatus::Signaled(_pid, signal, core_dumped) => Err(WaitError::Signaled {
+                signal,
+                core_dumped,
+            }),
+            WaitStatus::Stopped(_pid, signal) => Err(WaitError::Stopped(signal)),
+            WaitStatus::Continued(_pid) => Err(WaitError::Continued),
+            status => Err(WaitError::Unexpected(status)),
         }
     }
+
+    /// Kills the process, which must then still be reaped with [`wait`](Self::wait).
+    pub(crate) fn kill(&self) -> nix::Result<()> {
+        nix::sys::signal::kill(self.pid, nix::sys::signal::Signal::SIGKILL)
+    }
 }
 
 pub(crate) fn return_from_process<T: Serialize + DeserializeOwned, F: FnOnce() -> T>(
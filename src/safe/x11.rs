@@ -1,4 +1,5 @@
 use std::ffi::CStr;
+use std::path::Path;
 
 pub(crate) struct DisplayWrapper {
     display: *mut x11::xlib::Display,
@@ -24,8 +25,17 @@ pub(crate) fn open_display(display: &CStr) -> Result<DisplayWrapper, FailedToOpe
     }
 }
 
-pub(crate) fn start_x_server(display: &str, vt: &str) -> std::io::Result<std::process::Child> {
-    std::process::Command::new("X").arg(display).arg(vt).spawn()
+pub(crate) fn start_x_server(
+    display: &str,
+    vt: &str,
+    auth: &Path,
+) -> std::io::Result<std::process::Child> {
+    std::process::Command::new("X")
+        .arg(display)
+        .arg(vt)
+        .arg("-auth")
+        .arg(auth)
+        .spawn()
 }
 
 pub(crate) fn poll_for_x_available(
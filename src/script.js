@@ -4,22 +4,76 @@
 
     const CALLBACKS = Symbol.for(__rust_objects.callback_secret);
 
+    function describe(value) {
+        if (typeof value === "string") {
+            return value;
+        } else if (value instanceof Error) {
+            return value.stack ? `${value.name}: ${value.message}\n${value.stack}` : String(value);
+        }
+
+        try {
+            return JSON.stringify(value);
+        } catch (e) {
+            return String(value);
+        }
+    }
+
+    function log(level, message, source, line, column) {
+        try {
+            local_webkit.messageHandlers.log.postMessage(JSON.stringify({
+                level: level,
+                message: message,
+                source: source || null,
+                line: line || null,
+                column: column || null,
+            }));
+        } catch (e) {
+            // Logging must never break the theme
+        }
+    }
+
+    ["debug", "log", "info", "warn", "error"].forEach(level => {
+        const original = console[level];
+
+        console[level] = function (...args) {
+            log(level, args.map(describe).join(" "));
+
+            if (original) {
+                original.apply(console, args);
+            }
+        };
+    });
+
+    window.addEventListener("error", event => {
+        log("error", `Uncaught ${describe(event.error || event.message)}`, event.filename, event.lineno, event.colno);
+    });
+
+    window.addEventListener("unhandledrejection", event => {
+        log("error", `Unhandled rejection ${describe(event.reason)}`);
+    });
+
     let counter = 0;
 
     class Callback {
         constructor() {
             this.callbacks = {};
+            this.events = {};
         }
 
-        create(fn) {
+        create(fn, on_event) {
             const id = counter++;
 
             this.callbacks[id] = fn;
+
+            if (on_event) {
+                this.events[id] = on_event;
+            }
+
             return id;
         }
 
-        send(handler, message, fn) {
-            const id = this.create(fn);
+        send(handler, message, fn, on_event) {
+            const id = this.create(fn, on_event);
 
             handler.postMessage(JSON.stringify({
                 id: id,
@@ -30,8 +84,17 @@
         call(id, value) {
             const fn = this.callbacks[id];
             delete this.callbacks[id];
+            delete this.events[id];
             fn(value);
         }
+
+        event(id, value) {
+            const fn = this.events[id];
+
+            if (fn) {
+                fn(value);
+            }
+        }
     }
 
     const callback = new Callback();
@@ -44,6 +107,68 @@
         }
     }
 
+    function settle(result, resolve, reject) {
+        if (result.ok) {
+            resolve();
+        } else {
+            reject(new WebDM.Error(result.error));
+        }
+    }
+
+    function power(handler) {
+        return new Promise((resolve, reject) => {
+            callback.send(handler, {}, result => {
+                settle(result, resolve, reject);
+            });
+        });
+    }
+
+    function converse(handler, message, cancel) {
+        const listeners = {
+            prompt: [],
+            info: [],
+            error: [],
+        };
+
+        let waiting = false;
+
+        const answer = response => {
+            if (waiting) {
+                waiting = false;
+                local_webkit.messageHandlers.answer.postMessage(JSON.stringify({
+                    response: typeof response === "string" ? response : null,
+                }));
+            }
+        };
+
+        const promise = new CancelPromise((resolve, reject) => {
+            callback.send(handler, message, result => {
+                waiting = false;
+                settle(result, resolve, reject);
+            }, event => {
+                if (event.type === "prompt") {
+                    waiting = true;
+
+                    if (listeners.prompt.length) {
+                        listeners.prompt.forEach(fn => fn(event, answer));
+                    } else {
+                        answer(null);
+                    }
+                } else {
+                    listeners[event.type].forEach(fn => fn(event));
+                }
+            });
+        }, cancel);
+
+        promise.canceled(() => answer(null));
+        promise.on = (type, fn) => {
+            listeners[type].push(fn);
+            return promise;
+        };
+
+        return promise;
+    }
+
     const WebDM = {
         Greeter: class {
             constructor() {
@@ -54,29 +179,25 @@
 
                 this.lock_hint = __rust_objects.lock_hint;
                 this.users_hidden = __rust_objects.hide_users;
+                this.last_user = __rust_objects.last_user;
                 this.hostname = __rust_objects.hostname;
 
                 this.default_session = map_null(__rust_objects.default_session, sess => new WebDM.Session(sess));
+                this.autologin = map_null(__rust_objects.autologin, autologin => new WebDM.Autologin(autologin));
 
                 this.sessions = __rust_objects.sessions.map(sess => new WebDM.Session(sess));
                 this.users = __rust_objects.users.map(user => new WebDM.User(user));
             }
 
             authenticate(username, password, cancel) {
-                const promise = new CancelPromise((resolve, reject) => {
-                    callback.send(local_webkit.messageHandlers.auth, {
-                        username: username,
-                        password: password,
-                    }, value => {
-                        if (value) {
-                            resolve();
-                        } else {
-                            reject();
-                        }
-                    });
+                return converse(local_webkit.messageHandlers.auth, {
+                    username: username,
+                    password: typeof password === "string" ? password : null,
                 }, cancel);
+            }
 
-                return promise;
+            change_password(cancel) {
+                return converse(local_webkit.messageHandlers.change_password, {}, cancel);
             }
 
             exit() {
@@ -87,28 +208,85 @@
                 const session = _session ? {
                     name: _session.name,
                     key: _session.key,
+                    id: _session.id,
+                    type: _session.type,
                     comment: _session.comment
                 } : __rust_objects.default_session;
 
                 console.log(session);
 
                 const promise = new Promise((resolve, reject) => {
-                    callback.send(local_webkit.messageHandlers.open_session, session, value => {
-                        if (value) {
-                            resolve();
-                        } else {
-                            reject();
-                        }
+                    callback.send(local_webkit.messageHandlers.open_session, session, result => {
+                        settle(result, resolve, reject);
                     });
                 });
 
                 return promise;
             }
 
-            // shutdown() { } // bool
-            // hibernate() { } // bool
-            // suspend() { } // bool
-            // restart() { } // bool
+            shutdown() {
+                return power(local_webkit.messageHandlers.shutdown);
+            }
+
+            hibernate() {
+                return power(local_webkit.messageHandlers.hibernate);
+            }
+
+            suspend() {
+                return power(local_webkit.messageHandlers.suspend);
+            }
+
+            restart() {
+                return power(local_webkit.messageHandlers.restart);
+            }
+        },
+        Autologin: class {
+            constructor(autologin) {
+                this.username = autologin.username;
+                this.session = map_null(autologin.session, sess => new WebDM.Session(sess));
+                this.timeout = autologin.timeout;
+                this.remaining = autologin.timeout;
+                this.canceled = false;
+                this.listeners = {
+                    tick: [],
+                    cancel: [],
+                };
+            }
+
+            on(type, fn) {
+                this.listeners[type].push(fn);
+                return this;
+            }
+
+            start() {
+                this.timer = setInterval(() => {
+                    this.remaining -= 1;
+                    this.listeners.tick.forEach(fn => fn(this.remaining));
+
+                    if (this.remaining <= 0) {
+                        clearInterval(this.timer);
+                        this.timer = null;
+
+                        callback.send(local_webkit.messageHandlers.autologin, {}, result => {
+                            if (result.ok) {
+                                window.webdm.open_session(this.session);
+                            } else {
+                                console.error("Automatic login failed:", result.error.message);
+                            }
+                        });
+                    }
+                }, 1000);
+            }
+
+            cancel() {
+                if (this.timer) {
+                    clearInterval(this.timer);
+                    this.timer = null;
+                    this.canceled = true;
+                    callback.send(local_webkit.messageHandlers.cancel_autologin, {}, () => {});
+                    this.listeners.cancel.forEach(fn => fn());
+                }
+            }
         },
         Config: class {
             constructor() {
@@ -121,15 +299,24 @@
                 Object.assign(this, {
                     name: sess.name,
                     key: sess.key,
+                    id: sess.id,
+                    type: sess.type,
                     comment: sess.comment,
                 });
             }
         },
+        Error: class extends Error {
+            constructor(error) {
+                super(error.message);
+                this.code = error.code;
+            }
+        },
         User: class {
             constructor(user) {
                 Object.assign(this, {
                     display_name: user.display_name,
                     username: user.username,
+                    last_session: map_null(user.last_session, sess => new WebDM.Session(sess)),
                 });
             }
         },
@@ -197,4 +384,11 @@
             }
         }
     });
+
+    if (window.webdm.autologin) {
+        const autologin = window.webdm.autologin;
+
+        window.addEventListener("keydown", () => autologin.cancel(), true);
+        autologin.start();
+    }
 })
\ No newline at end of file
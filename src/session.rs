@@ -0,0 +1,227 @@
+use crate::freedesktop;
+use crate::message;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::Path;
+
+const JS_NUMBER_MASK: u64 = (1 << 53) - 1;
+
+const LOGIN_DEFS: &str = "/etc/login.defs";
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const DEFAULT_SUPATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The desktop names WebDM matches against `OnlyShowIn` and `NotShowIn`.
+const DESKTOP_NAMES: &[&str] = &["WebDM"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SessionType {
+    X11,
+    Wayland,
+}
+
+impl SessionType {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            SessionType::X11 => "x11",
+            SessionType::Wayland => "wayland",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SessionEntry {
+    pub(crate) id: String,
+    pub(crate) typ: SessionType,
+    pub(crate) entry: freedesktop::Entry,
+}
+
+pub(crate) struct Sessions {
+    pub(crate) entries: HashMap<u64, SessionEntry>,
+    pub(crate) list: Vec<message::Session>,
+    pub(crate) default: Option<message::Session>,
+}
+
+impl Sessions {
+    pub(crate) fn discover(
+        x11_path: &str,
+        wayland_path: &str,
+        default_name: Option<&String>,
+        locale: Option<&str>,
+    ) -> Self {
+        let mut sessions = Sessions {
+            entries: HashMap::new(),
+            list: vec![],
+            default: None,
+        };
+
+        sessions.scan(x11_path, SessionType::X11, default_name, locale);
+        sessions.scan(wayland_path, SessionType::Wayland, default_name, locale);
+
+        if sessions.default.is_none() {
+            sessions.default = sessions.list.iter().next().cloned();
+        }
+
+        sessions
+    }
+
+    fn scan(
+        &mut self,
+        path: &str,
+        typ: SessionType,
+        default_name: Option<&String>,
+        locale: Option<&str>,
+    ) {
+        let default = &mut self.default;
+        let list = &mut self.list;
+
+        let entries: Vec<_> = match std::fs::read_dir(path) {
+            Err(_) => vec![],
+            Ok(entries) => entries
+                .into_iter()
+                .filter_map(|entry| {
+                    entry.ok().and_then(|entry| {
+                        let key = entry.path();
+                        match freedesktop::Entry::parse(&key) {
+                            Err(e) => {
+                                warn!("Error during parsing of {:#?}: {:?}", entry.path(), e);
+                                None
+                            }
+                            Ok(entry) => {
+                                if entry.typ != freedesktop::EntryType::Application {
+                                    info!("Excluding session {:#?}: it is not an application", key);
+                                    None
+                                } else if let Some(reason) = entry.exclusion_reason(DESKTOP_NAMES) {
+                                    info!("Excluding session {:#?}: {}", key, reason);
+                                    None
+                                } else {
+                                    let id = key
+                                        .file_stem()
+                                        .map(|stem| stem.to_string_lossy().into_owned())
+                                        .unwrap_or_default();
+
+                                    let key = crate::convenience::hash(
+                                        key.to_string_lossy().into_owned(),
+                                    ) & JS_NUMBER_MASK;
+
+                                    let new_default = default_name
+                                        .map(|default| default == &entry.name)
+                                        .unwrap_or(false);
+
+                                    let session = message::Session {
+                                        key,
+                                        id: id.clone(),
+                                        typ,
+                                        name: entry.name_in(locale).to_owned(),
+                                        comment: entry.comment_in(locale).unwrap_or("").to_owned(),
+                                    };
+
+                                    if new_default && default.is_none() {
+                                        *default = Some(session.clone());
+                                    };
+
+                                    list.push(session);
+
+                                    Some((key, SessionEntry { id, typ, entry }))
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect(),
+        };
+
+        self.entries.extend(entries);
+    }
+
+    pub(crate) fn with_id(&self, id: &str) -> Option<&message::Session> {
+        self.list.iter().find(|session| session.id == id)
+    }
+
+    /// The session with the given untranslated or localized name, or the default session if no
+    /// name is given.
+    pub(crate) fn named(&self, name: Option<&String>) -> Option<message::Session> {
+        match name {
+            Some(name) => self
+                .list
+                .iter()
+                .find(|session| {
+                    &session.name == name
+                        || self
+                            .entries
+                            .get(&session.key)
+                            .map(|session| &session.entry.name == name)
+                            .unwrap_or(false)
+                })
+                .cloned(),
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// The PATH a login would get according to `ENV_PATH` or `ENV_SUPATH` in /etc/login.defs.
+pub(crate) fn login_path(root: bool) -> String {
+    let key = if root { "ENV_SUPATH" } else { "ENV_PATH" };
+
+    std::fs::read_to_string(LOGIN_DEFS)
+        .ok()
+        .and_then(|contents| {
+            contents.lines().find_map(|line| {
+                let mut parts = line.trim().split_whitespace();
+
+                if parts.next()? != key {
+                    return None;
+                }
+
+                let value = parts.next()?;
+                Some(value.trim_start_matches("PATH=").to_owned())
+            })
+        })
+        .unwrap_or_else(|| if root { DEFAULT_SUPATH } else { DEFAULT_PATH }.to_owned())
+}
+
+/// Creates the runtime directory of a user whose PAM stack does not, accessible only to that
+/// user. An existing directory is only accepted if the user owns it.
+pub(crate) fn ensure_runtime_dir(path: &Path, uid: u32, gid: u32) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(parent)?;
+    }
+
+    let created = match std::fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(()) => true,
+        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => false,
+        Err(e) => return Err(e),
+    };
+
+    let metadata = std::fs::symlink_metadata(path)?;
+
+    if !metadata.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "not a directory",
+        ));
+    }
+
+    if created {
+        nix::unistd::chown(
+            path,
+            Some(nix::unistd::Uid::from_raw(uid)),
+            Some(nix::unistd::Gid::from_raw(gid)),
+        )
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    } else if metadata.uid() != uid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "owned by another user",
+        ));
+    }
+
+    Ok(())
+}
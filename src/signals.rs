@@ -0,0 +1,179 @@
+use log::{error, info, warn};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::unistd::Pid;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a session may take to exit after SIGTERM before it is killed.
+const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Children {
+    greeter: Option<Pid>,
+    session: Option<Pid>,
+}
+
+#[derive(Default)]
+struct Shared {
+    terminating: AtomicBool,
+    reload: AtomicBool,
+    children: Mutex<Children>,
+    wake: Condvar,
+}
+
+/// Handles SIGTERM, SIGINT and SIGHUP on a dedicated thread. Termination stops the greeter and
+/// the session; everything else is torn down by the main thread as its waits return.
+#[derive(Clone)]
+pub(crate) struct Signals(Arc<Shared>);
+
+fn handled() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGTERM);
+    set.add(Signal::SIGINT);
+    set.add(Signal::SIGHUP);
+    set
+}
+
+impl Signals {
+    /// Blocks the handled signals and starts the thread waiting for them. Must be called before
+    /// any other thread is started, so every thread inherits the mask.
+    pub(crate) fn install() -> nix::Result<Self> {
+        let set = handled();
+        set.thread_block()?;
+
+        let signals = Signals(Arc::new(Shared::default()));
+        let handler = signals.clone();
+
+        std::thread::spawn(move || loop {
+            match set.wait() {
+                Ok(Signal::SIGHUP) => {
+                    info!("Received SIGHUP, reloading configuration before the next greeter");
+                    handler.0.reload.store(true, Ordering::SeqCst);
+                }
+                Ok(signal) => handler.terminate(signal),
+                Err(e) => error!("Could not wait for signals: {}", e),
+            }
+        });
+
+        Ok(signals)
+    }
+
+    /// Unblocks the handled signals, for forked processes that do not exec.
+    pub(crate) fn unblock() -> nix::Result<()> {
+        handled().thread_unblock()
+    }
+
+    fn terminate(&self, signal: Signal) {
+        info!("Received {:?}, shutting down", signal);
+        self.0.terminating.store(true, Ordering::SeqCst);
+
+        let children = self
+            .0
+            .children
+            .lock()
+            .expect("Children mutex to be un-poisoned");
+        self.0.wake.notify_all();
+
+        if let Some(pid) = children.greeter {
+            kill(pid, Signal::SIGTERM).ok();
+        }
+
+        if let Some(pid) = children.session {
+            self.stop_session(pid);
+        }
+    }
+
+    /// Asks the session's process group to exit, killing it if it is still running after the
+    /// grace period.
+    fn stop_session(&self, pid: Pid) {
+        info!("Stopping session");
+        kill(Pid::from_raw(-pid.as_raw()), Signal::SIGTERM)
+            .or_else(|_| kill(pid, Signal::SIGTERM))
+            .ok();
+
+        let shared = self.0.clone();
+
+        std::thread::spawn(move || {
+            std::thread::sleep(SESSION_GRACE_PERIOD);
+
+            let children = shared
+                .children
+                .lock()
+                .expect("Children mutex to be un-poisoned");
+
+            if children.session == Some(pid) {
+                warn!("Session did not exit in time, killing it");
+                kill(Pid::from_raw(-pid.as_raw()), Signal::SIGKILL)
+                    .or_else(|_| kill(pid, Signal::SIGKILL))
+                    .ok();
+            }
+        });
+    }
+
+    pub(crate) fn terminating(&self) -> bool {
+        self.0.terminating.load(Ordering::SeqCst)
+    }
+
+    /// Whether the configuration should be reloaded, resetting the request.
+    pub(crate) fn take_reload(&self) -> bool {
+        self.0.reload.swap(false, Ordering::SeqCst)
+    }
+
+    /// Registers the running greeter process. It must be unregistered before it is reaped, so a
+    /// recycled pid is never signalled.
+    pub(crate) fn set_greeter(&self, pid: Option<Pid>) {
+        let mut children = self
+            .0
+            .children
+            .lock()
+            .expect("Children mutex to be un-poisoned");
+        children.greeter = pid;
+
+        if let (Some(pid), true) = (pid, self.terminating()) {
+            kill(pid, Signal::SIGTERM).ok();
+        }
+    }
+
+    /// Registers the running session process. It must be unregistered before it is reaped.
+    pub(crate) fn set_session(&self, pid: Option<Pid>) {
+        let mut children = self
+            .0
+            .children
+            .lock()
+            .expect("Children mutex to be un-poisoned");
+        children.session = pid;
+
+        if let (Some(pid), true) = (pid, self.terminating()) {
+            self.stop_session(pid);
+        }
+    }
+
+    /// Sleeps for `duration`, returning false if termination was requested in the meantime.
+    pub(crate) fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        let mut children = self
+            .0
+            .children
+            .lock()
+            .expect("Children mutex to be un-poisoned");
+
+        while !self.terminating() {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return true;
+            }
+
+            children = self
+                .0
+                .wake
+                .wait_timeout(children, deadline - now)
+                .expect("Children mutex to be un-poisoned")
+                .0;
+        }
+
+        false
+    }
+}
@@ -0,0 +1,108 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use users::os::unix::UserExt;
+
+use crate::safe;
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Most of `~/.dmrc` that is read, the session is expected near the top
+const DMRC_LIMIT: u64 = 4096;
+
+/// What WebDM remembers between greeter runs.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct State {
+    pub(crate) last_user: Option<String>,
+    /// Desktop file id of the last session chosen by each user
+    #[serde(default)]
+    pub(crate) sessions: HashMap<String, String>,
+}
+
+impl State {
+    pub(crate) fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring invalid state file {}: {}", path.display(), e);
+                State::default()
+            }),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                warn!("Could not read state file {}: {}", path.display(), e);
+                State::default()
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let contents = toml::to_string(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub(crate) fn remember(&mut self, username: &str, session: &str) {
+        self.last_user = Some(username.to_owned());
+        self.sessions
+            .insert(username.to_owned(), session.to_owned());
+    }
+
+    /// The last session of a user, falling back to `~/.dmrc` when `dmrc` is set.
+    pub(crate) fn last_session(&self, user: &users::User, dmrc: bool) -> Option<String> {
+        self.sessions
+            .get(&*user.name().to_string_lossy())
+            .cloned()
+            .or_else(|| if dmrc { dmrc_session(user) } else { None })
+    }
+}
+
+/// Reads the session from `~/.dmrc` with the credentials of its owner, ignoring symlinks and
+/// anything but a regular file.
+fn dmrc_session(user: &users::User) -> Option<String> {
+    let path = user.home_dir().join(".dmrc");
+    let file = safe::libc::as_user_fs(user.uid(), user.primary_group_id(), || {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(&path)
+    });
+
+    let file = match file {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Could not open {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    if !file.metadata().ok()?.file_type().is_file() {
+        warn!("Ignoring {}, it is not a regular file", path.display());
+        return None;
+    }
+
+    let mut in_desktop = false;
+
+    for line in BufReader::new(file.take(DMRC_LIMIT)).lines() {
+        let line = line.ok()?;
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            in_desktop = line == "[Desktop]";
+        } else if in_desktop && line.starts_with("Session=") {
+            let session = line["Session=".len()..].trim();
+            return Some(session.trim_end_matches(".desktop").to_owned());
+        }
+    }
+
+    None
+}
@@ -1,13 +1,20 @@
 use glib::translate::{FromGlibPtrBorrow, ToGlibPtr};
 use gtk::Cast;
+use log::warn;
 use serde::Deserialize;
+use url::percent_encoding::percent_decode;
 use webkit2gtk::{
     JavascriptResult, NavigationPolicyDecision, NavigationPolicyDecisionExt, PolicyDecisionExt,
     PolicyDecisionType, ResponsePolicyDecision, ResponsePolicyDecisionExt, URIRequestExt,
-    URIResponseExt, UserContentInjectedFrames, UserContentManager, UserContentManagerExt,
-    UserScript, UserScriptInjectionTime, WebView, WebViewExt,
+    URIResponseExt, URISchemeRequest, URISchemeRequestExt, UserContentInjectedFrames,
+    UserContentManager, UserContentManagerExt, UserScript, UserScriptInjectionTime, WebContext,
+    WebContextExt, WebView, WebViewExt,
 };
 
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
 #[derive(Debug)]
 pub(crate) enum MessageError {
     NoContext(JavascriptResult),
@@ -35,7 +42,12 @@ pub(crate) trait UserContentManagerHelpers {
 
 pub(crate) trait WebViewHelpers {
     fn respond(&self, sym: &str, id: u64, js: impl core::fmt::Display);
-    fn only_accept_from(&self, host: &'static str, port: u16);
+    fn notify(&self, sym: &str, id: u64, js: impl core::fmt::Display);
+    fn only_accept_from(&self, scheme: &'static str, host: &'static str);
+}
+
+pub(crate) trait WebContextHelpers {
+    fn serve_directory(&self, scheme: &str, host: &'static str, root: PathBuf);
 }
 
 impl UserContentManagerHelpers for UserContentManager {
@@ -112,18 +124,109 @@ unsafe extern "C" fn script_message_received_trampoline<P>(
     )
 }
 
-fn allow_uri(uri: &str, host: &str, port: u16) -> bool {
+fn allow_uri(uri: &str, scheme: &str, host: &str) -> bool {
     if let Ok(url) = url::Url::parse(uri) {
-        if let (Some(h), Some(p)) = (url.host_str(), url.port()) {
-            host == h && port == p
-        } else {
-            false
-        }
+        url.scheme() == scheme && url.host_str() == Some(host)
     } else {
         false
     }
 }
 
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => "text/html",
+        Some("js") => "application/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Maps the path of a request onto a file inside `root`, refusing anything that would escape it.
+/// `root` must be canonical.
+fn resolve(root: &Path, uri: &str, host: &str) -> Option<PathBuf> {
+    let url = url::Url::parse(uri).ok()?;
+
+    if url.host_str() != Some(host) {
+        return None;
+    }
+
+    let mut path = root.to_path_buf();
+
+    for segment in url.path_segments()? {
+        let segment: Vec<u8> = percent_decode(segment.as_bytes()).collect();
+
+        match &segment[..] {
+            b"" => continue,
+            b"." | b".." => return None,
+            segment if segment.contains(&b'/') || segment.contains(&0) => return None,
+            segment => path.push(OsStr::from_bytes(segment)),
+        }
+    }
+
+    if path.is_dir() {
+        path.push("index.html");
+    }
+
+    // Symlinks inside the theme may not lead outside of it
+    let path = std::fs::canonicalize(path).ok()?;
+
+    if path.starts_with(root) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn respond_with_file(request: &URISchemeRequest, root: &Path, host: &str) {
+    let uri = request
+        .get_uri()
+        .map(|uri| uri.to_string())
+        .unwrap_or_default();
+
+    let contents = resolve(root, &uri, host).and_then(|path| {
+        std::fs::read(&path)
+            .map(|contents| (content_type(&path), contents))
+            .ok()
+    });
+
+    match contents {
+        Some((mime, contents)) => {
+            let length = contents.len() as i64;
+            let stream = gio::MemoryInputStream::new_from_bytes(&glib::Bytes::from(&contents[..]));
+
+            request.finish(&stream, length, Some(mime));
+        }
+        None => {
+            warn!("Theme resource not found: {}", uri);
+            request.finish_error(&mut glib::Error::new(
+                gio::IOErrorEnum::NotFound,
+                &format!("Not found: {}", uri),
+            ));
+        }
+    }
+}
+
+impl WebContextHelpers for WebContext {
+    fn serve_directory(&self, scheme: &str, host: &'static str, root: PathBuf) {
+        let root = std::fs::canonicalize(&root).unwrap_or(root);
+
+        self.register_uri_scheme(scheme, move |request| {
+            respond_with_file(request, &root, host)
+        });
+    }
+}
+
 impl WebViewHelpers for WebView {
     fn respond(&self, sym: &str, id: u64, js: impl core::fmt::Display) {
         let code = format!(
@@ -136,7 +239,18 @@ impl WebViewHelpers for WebView {
         self.run_javascript(&code, None, |_| {});
     }
 
-    fn only_accept_from(&self, host: &'static str, port: u16) {
+    fn notify(&self, sym: &str, id: u64, js: impl core::fmt::Display) {
+        let code = format!(
+            "window[Symbol.for('{}')].event({}, {});",
+            sym,
+            serde_json::json!(id),
+            js
+        );
+
+        self.run_javascript(&code, None, |_| {});
+    }
+
+    fn only_accept_from(&self, scheme: &'static str, host: &'static str) {
         self.connect_decide_policy(move |_, decision, decision_type| match decision_type {
             PolicyDecisionType::NavigationAction => {
                 let decision = decision.downcast_ref::<NavigationPolicyDecision>().unwrap();
@@ -145,7 +259,7 @@ impl WebViewHelpers for WebView {
                     .get_navigation_action()
                     .and_then(|action| action.get_request().and_then(|request| request.get_uri()))
                 {
-                    if allow_uri(uri.as_str(), host, port) {
+                    if allow_uri(uri.as_str(), scheme, host) {
                         return true;
                     }
                 }
@@ -160,7 +274,7 @@ impl WebViewHelpers for WebView {
                     .get_response()
                     .and_then(|respone| respone.get_uri())
                 {
-                    if allow_uri(uri.as_str(), host, port) {
+                    if allow_uri(uri.as_str(), scheme, host) {
                         return true;
                     }
                 }
@@ -0,0 +1,176 @@
+#[cfg(test)]
+mod tests;
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+const FAMILY_WILD: u16 = 0xffff;
+const MIT_MAGIC_COOKIE: &str = "MIT-MAGIC-COOKIE-1";
+
+#[derive(Clone)]
+pub(crate) struct Cookie([u8; 16]);
+
+impl Cookie {
+    pub(crate) fn generate() -> std::io::Result<Self> {
+        let mut cookie = [0u8; 16];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut cookie)?;
+
+        Ok(Cookie(cookie))
+    }
+}
+
+fn push_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+fn read_u16(buf: &[u8]) -> Option<(u16, &[u8])> {
+    if buf.len() < 2 {
+        return None;
+    }
+
+    Some((u16::from_be_bytes([buf[0], buf[1]]), &buf[2..]))
+}
+
+fn read_field(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = read_u16(buf)?;
+    let len = len as usize;
+
+    if rest.len() < len {
+        return None;
+    }
+
+    Some(rest.split_at(len))
+}
+
+/// Splits an authority file into its raw entries along with their display numbers, ignoring a
+/// truncated trailing entry.
+fn entries(mut buf: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut entries = vec![];
+
+    loop {
+        let start = buf;
+        let entry = read_u16(buf).and_then(|(_family, rest)| {
+            let (_address, rest) = read_field(rest)?;
+            let (number, rest) = read_field(rest)?;
+            let (_name, rest) = read_field(rest)?;
+            let (_data, rest) = read_field(rest)?;
+
+            Some((number, rest))
+        });
+
+        match entry {
+            Some((number, rest)) => {
+                entries.push((&start[..start.len() - rest.len()], number));
+                buf = rest;
+            }
+            None => return entries,
+        }
+    }
+}
+
+fn entry(display: &str, cookie: &Cookie) -> Vec<u8> {
+    let mut entry = vec![];
+    entry.extend_from_slice(&FAMILY_WILD.to_be_bytes());
+    push_field(&mut entry, &[]);
+    push_field(&mut entry, display_number(display).as_bytes());
+    push_field(&mut entry, MIT_MAGIC_COOKIE.as_bytes());
+    push_field(&mut entry, &cookie.0);
+    entry
+}
+
+fn display_number(display: &str) -> &str {
+    display
+        .trim_start_matches(':')
+        .split('.')
+        .next()
+        .unwrap_or("0")
+}
+
+/// Creates the directory holding the authority files. Other users may traverse but not list it,
+/// so the greeter user can reach its own authority file.
+pub(crate) fn create_runtime_dir(path: &Path) -> std::io::Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o711)
+        .create(path)
+}
+
+/// Writes an authority file with a single wildcard entry granting access to `display`.
+///
+/// When `owner` is given the file is handed over to that uid and gid. Symlinks are never
+/// followed, as the file may live in a directory writable by the owner.
+pub(crate) fn write(
+    path: &Path,
+    display: &str,
+    cookie: &Cookie,
+    owner: Option<(u32, u32)>,
+) -> std::io::Result<()> {
+    let entry = entry(display, cookie);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+
+    if let Some((uid, gid)) = owner {
+        if unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    file.write_all(&entry)
+}
+
+/// Adds an entry granting access to `display` to the authority file at `path`, replacing older
+/// entries for the same display and keeping all others, such as those of ssh X forwarding.
+///
+/// The file is replaced atomically through a temporary file next to it. Meant to be called with
+/// the credentials of the user owning the file.
+pub(crate) fn update(path: &Path, display: &str, cookie: &Cookie) -> std::io::Result<()> {
+    let existing = match std::fs::read(path) {
+        Ok(existing) => existing,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+
+    let number = display_number(display).as_bytes();
+    let mut contents: Vec<u8> = entries(&existing)
+        .into_iter()
+        .filter(|(_, entry_number)| *entry_number != number)
+        .flat_map(|(entry, _)| entry.iter().cloned())
+        .collect();
+    contents.extend(entry(display, cookie));
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "No file name"))?;
+    let temporary = path.with_file_name(format!(
+        ".{}.webdm-{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    std::fs::remove_file(&temporary).ok();
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&temporary)
+        .and_then(|mut file| file.write_all(&contents).and_then(|_| file.sync_all()))
+        .and_then(|_| std::fs::rename(&temporary, path));
+
+    if written.is_err() {
+        std::fs::remove_file(&temporary).ok();
+    }
+
+    written
+}
@@ -0,0 +1,67 @@
+use super::*;
+
+fn cookie(byte: u8) -> Cookie {
+    Cookie([byte; 16])
+}
+
+fn temporary_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("webdm-xauth-{}-{}", std::process::id(), name));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn round_trips_entry() {
+    let written = entry(":1.0", &cookie(1));
+
+    assert_eq!(entries(&written), vec![(&written[..], &b"1"[..])]);
+}
+
+#[test]
+fn ignores_truncated_trailing_entry() {
+    let first = entry(":0", &cookie(1));
+    let second = entry(":1", &cookie(2));
+
+    for len in 0..second.len() {
+        let mut buf = first.clone();
+        buf.extend_from_slice(&second[..len]);
+
+        assert_eq!(entries(&buf), vec![(&first[..], &b"0"[..])]);
+    }
+}
+
+#[test]
+fn updates_entry_of_display() {
+    let dir = temporary_dir("update");
+    let path = dir.join("Xauthority");
+
+    let other = entry(":0", &cookie(1));
+    let mut existing = other.clone();
+    existing.extend(entry(":1", &cookie(2)));
+    existing.extend_from_slice(&entry(":2", &cookie(3))[..10]);
+    std::fs::write(&path, &existing).unwrap();
+
+    update(&path, ":1.0", &cookie(4)).unwrap();
+    let updated = std::fs::read(&path).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    let replaced = entry(":1", &cookie(4));
+    assert_eq!(
+        entries(&updated),
+        vec![(&other[..], &b"0"[..]), (&replaced[..], &b"1"[..])]
+    );
+    assert_eq!(updated.len(), other.len() + replaced.len());
+}
+
+#[test]
+fn update_creates_missing_file() {
+    let dir = temporary_dir("create");
+    let path = dir.join("Xauthority");
+
+    update(&path, ":0", &cookie(1)).unwrap();
+    let created = std::fs::read(&path).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(created, entry(":0", &cookie(1)));
+}